@@ -1,29 +1,194 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io;
 use std::process::exit;
+use std::time::{Duration, Instant};
 
-mod rvm;
-
+extern crate rvm;
 #[macro_use] extern crate log;
 
-fn main() {
-	let filepath: String;
-	let bytecode: rvm::Bytecode;
-	if let Some (arg1) = env::args().nth(1) {
-		filepath = arg1;
-        } else {
-                println!("Usage: ./rvm <path_to_assembly_code>");
-                exit(1);
-        }
+use rvm::word::Word;
 
+fn usage() -> ! {
+	println!("Usage: ./rvm <path_to_assembly_code>");
+	println!("       (use - as path_to_assembly_code to read the program from stdin)");
+	println!("       ./rvm run [--trace <path_to_trace.jsonl>] [--max-steps <n>] [--timeout <ms>] <path_to_assembly_code|path_to_bytecode.rvb>");
+	println!("       ./rvm asm <path_to_assembly_code> [-o <path_to_bytecode.rvb>] [--listing <path_to_listing.lst>]");
+	println!("       ./rvm disasm <path_to_assembly_code|path_to_bytecode.rvb>");
+	println!("       ./rvm debug <path_to_assembly_code|path_to_bytecode.rvb>");
+	exit(1);
+}
+
+fn assemble(filepath: &str) -> rvm::parser::Assembly {
 	debug!("Assembling {}", filepath);
-	match rvm::parser::assemble_file(&filepath) {
-		Ok(assembly) => { debug!("success!"); bytecode = assembly; },
-		_ => { println!("failed to parse file {}", filepath); exit(1) }
+	let result = if filepath == "-" {
+		let stdin = io::stdin();
+		rvm::parser::assemble_reader(stdin.lock(), "<stdin>")
+	} else {
+		rvm::parser::assemble_file(filepath)
+	};
+	match result {
+		Ok(assembly) => { debug!("success!"); assembly },
+		Err(diagnostics) => {
+			for diagnostic in &diagnostics {
+				println!("{}\n", diagnostic);
+			}
+			println!("failed to parse file {}: {} error(s)", filepath, diagnostics.len());
+			exit(1)
+		}
+	}
+}
+
+fn load(filepath: &str) -> rvm::rvb::Program {
+	if !rvm::rvb::is_bytecode(filepath) {
+		return assemble(filepath).program()
+	}
+
+	debug!("Loading {}", filepath);
+	match rvm::rvb::load(filepath) {
+		Ok(program) => program,
+		Err(error) => { println!("failed to load file {}\n\t-> Hint: {}", filepath, error); exit(1) }
+	}
+}
+
+fn asm(args: &[String]) {
+	let mut filepath = None;
+	let mut output = None;
+	let mut listing = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-o" => output = Some(args.next().unwrap_or_else(|| usage()).to_owned()),
+			"--listing" => listing = Some(args.next().unwrap_or_else(|| usage())),
+			_ if filepath.is_none() => filepath = Some(arg),
+			_ => usage()
+		}
+	}
+	let filepath = filepath.unwrap_or_else(|| usage());
+	let output = output.unwrap_or_else(|| format!("{}.rvb", filepath.trim_end_matches(".rvm")));
+	let assembly = assemble(filepath);
+	if let Some(listing) = listing {
+		if let Err(error) = File::create(listing).and_then(|mut file| rvm::listing::write_listing(&assembly, &mut file)) {
+			println!("failed to write file {}\n\t-> Hint: {}", listing, error);
+			exit(1)
+		}
+	}
+	if let Err(error) = rvm::rvb::save(&output, &assembly.program()) {
+		println!("failed to write file {}\n\t-> Hint: {}", output, error);
+		exit(1)
+	}
+}
+
+fn disasm(args: &[String]) {
+	let program = match args {
+		[filepath] => load(filepath),
+		_ => usage()
+	};
+	match rvm::disasm::disassemble_program(&program) {
+		Ok(sloc) => print!("{}", sloc),
+		Err((index, _)) => { println!("failed to disassemble instruction 0x{:04x} at index 0x{:x}", program.bytecode[index], index); exit(1) }
+	}
+}
+
+fn debug(args: &[String]) {
+	let (program, labels) = match args {
+		[filepath] if rvm::rvb::is_bytecode(filepath) => (load(filepath), HashMap::new()),
+		[filepath] => { let assembly = assemble(filepath); (assembly.program(), assembly.labels) },
+		_ => usage()
+	};
+	let stdin = io::stdin();
+	let result = if program.is_wide() {
+		rvm::debugger::Debugger::<u16>::new(program, labels).repl(stdin.lock(), io::stdout())
+	} else {
+		rvm::debugger::Debugger::<u8>::new(program, labels).repl(stdin.lock(), io::stdout())
+	};
+	if result.is_err() {
+		exit(1)
+	}
+}
+
+fn exit_code(error: &rvm::VMError) -> i32 {
+	match *error {
+		rvm::VMError::VMHaltError => 0,
+		rvm::VMError::VMContextFetchNextError { .. } | rvm::VMError::VMContextFetchInvalidError { .. } => 2,
+		rvm::VMError::VMInvalidOpcodeError | rvm::VMError::VMInvalidTargetError | rvm::VMError::VMInvalidValueError => 3,
+		rvm::VMError::VMRegisterOverflowError => 4,
+		rvm::VMError::VMStackOverflowError | rvm::VMError::VMStackInvalidAccessError => 5,
+		rvm::VMError::VMInterruptError | rvm::VMError::VMUnimplementedError(_) | rvm::VMError::VMSyscallError(_) => 6,
+		rvm::VMError::VMBudgetExhausted { .. } => 7
+	}
+}
+
+fn run(args: &[String]) {
+	let mut filepath = None;
+	let mut trace = None;
+	let mut budget = rvm::vm::Budget::default();
+	let mut timeout = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--trace" => trace = Some(args.next().unwrap_or_else(|| usage())),
+			"--max-steps" => budget.max_steps = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())),
+			"--timeout" => timeout = Some(Duration::from_millis(args.next().and_then(|ms| ms.parse().ok()).unwrap_or_else(|| usage()))),
+			_ if filepath.is_none() => filepath = Some(arg),
+			_ => usage()
+		}
 	}
+	let filepath = filepath.unwrap_or_else(|| usage());
+
+	let program = load(filepath);
+	budget.deadline = timeout.map(|timeout| Instant::now() + timeout);
+	if program.is_wide() {
+		execute(rvm::vm::Context::<u16>::from(program), budget, trace)
+	} else {
+		execute(rvm::vm::Context::<u8>::from(program), budget, trace)
+	}
+}
 
-	match rvm::vm::run(bytecode) {
+fn execute<W: Word>(mut context: rvm::vm::Context<W>, budget: rvm::vm::Budget, trace: Option<&String>) {
+	context.budget = budget;
+	if let Some(trace) = trace {
+		match rvm::trace::Tracer::create(trace) {
+			Ok(tracer) => context.tracer = Some(tracer),
+			Err(error) => { println!("failed to create trace file {}\n\t-> Hint: {}", trace, error); exit(1) }
+		}
+	}
+	match rvm::vm::run_context(context) {
 		Ok(context) => debug!("Execution ok\nbacktrace registers: {:?}\nbacktrace stack: {:?}", context.registers, context.stack),
-		_ => println!("Error in execution")
+		Err(fault) => {
+			println!("{}", fault);
+			debug!("backtrace registers: {:?}\nbacktrace stack: {:?}", fault.context.registers, fault.context.stack);
+			exit(exit_code(&fault.error))
+		}
+	}
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	match args.first().map(|s| s.as_str()) {
+		Some("asm") => asm(&args[1..]),
+		Some("run") => run(&args[1..]),
+		Some("disasm") => disasm(&args[1..]),
+		Some("debug") => debug(&args[1..]),
+		Some(_) if args.len() == 1 => run(&args),
+		Some(_) => usage(),
+		None => usage()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn exit_codes_follow_the_fault_class() {
+		assert_eq!(exit_code(&rvm::VMError::VMHaltError), 0);
+		assert_eq!(exit_code(&rvm::VMError::VMContextFetchInvalidError { bank: 0, offset: 3 }), 2);
+		assert_eq!(exit_code(&rvm::VMError::VMInvalidOpcodeError), 3);
+		assert_eq!(exit_code(&rvm::VMError::VMRegisterOverflowError), 4);
+		assert_eq!(exit_code(&rvm::VMError::VMStackOverflowError), 5);
+		assert_eq!(exit_code(&rvm::VMError::VMSyscallError("boom".into())), 6);
+		assert_eq!(exit_code(&rvm::VMError::VMBudgetExhausted { steps: 10, pc: 0 }), 7);
 	}
-	
 }
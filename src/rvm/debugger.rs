@@ -0,0 +1,331 @@
+use std::char;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Cursor, Write};
+use super::*;
+use super::disasm::{disassemble_at, instruction_length};
+use super::vm::{Context, StepOutcome, Vm, Watchpoint};
+use super::word::Word;
+
+const HELP: &str = "Commands:
+	s, step [n]		execute n instructions (default 1)
+	c, continue		execute until a breakpoint, halt or fault
+	b, break [index|label]	set a breakpoint, list breakpoints without argument
+	d, delete <index|label>	remove a breakpoint
+	w, watch <reg> [value]	stop when a register is written (or changes to value)
+	w, watch stack <index>	stop when a stack slot is read or written
+	w, watch		list watchpoints, unwatch removes all
+	r, regs			print registers
+	stack [n]		dump n stack slots around rd (default 8)
+	disas [n]		disassemble n instructions around rn (default 4)
+	set <reg> <value>	set a register
+	set stack <index> <value>	set a stack slot
+	restart			reload the program
+	q, quit			leave the debugger
+	an empty line repeats the last command";
+
+pub struct Debugger<T: Word = Rsize> {
+	pub vm: Vm<T>,
+	pub breakpoints: Vec<usize>,
+	program: rvb::Program,
+	labels: HashMap<String, usize>,
+	last_command: String
+}
+
+fn parse_number(token: &str) -> Option<usize> {
+	if let Some(hex) = token.strip_prefix("0x") {
+		usize::from_str_radix(hex, 16).ok()
+	} else {
+		token.parse::<usize>().ok()
+	}
+}
+
+fn parse_register(token: &str) -> Option<Rsize> {
+	REGISTERS.iter().position(|name| *name == token).map(|index| index as Rsize)
+}
+
+impl<T: Word> Debugger<T> {
+	pub fn new(program: rvb::Program, labels: HashMap<String, usize>) -> Debugger<T> {
+		Debugger { vm: Debugger::load(&program), breakpoints: Vec::new(), program, labels, last_command: String::new() }
+	}
+
+	fn load(program: &rvb::Program) -> Vm<T> {
+		let mut context = Context::from(program.clone());
+		context.io.input = Box::new(Cursor::new(Vec::new()));
+		Vm::new(context)
+	}
+
+	pub fn restart(&mut self) {
+		let watchpoints = self.vm.watchpoints.clone();
+		self.vm = Debugger::load(&self.program);
+		self.vm.watchpoints = watchpoints;
+	}
+
+	fn resolve(&self, token: &str) -> Option<usize> {
+		parse_number(token).or_else(|| self.labels.get(token).cloned())
+	}
+
+	fn label_at(&self, index: usize) -> Option<&str> {
+		self.labels.iter().find(|&(_, address)| *address == index).map(|(label, _)| label.as_str())
+	}
+
+	fn print_instruction<W: Write>(&self, out: &mut W, index: usize) -> io::Result<()> {
+		if let Some(label) = self.label_at(index) {
+			writeln!(out, "{}:", label)?;
+		}
+		let marker = if index == self.vm.pc() { "=>" } else { "  " };
+		let breakpoint = if self.breakpoints.contains(&index) { "*" } else { " " };
+		match self.program.bytecode.get(index) {
+			Some(instruction) => {
+				let sloc = disassemble_at(&self.program.bytecode, index, T::WIDE).unwrap_or_else(|_| "<invalid>".to_owned());
+				match self.vm.context.debug_info.get(index) {
+					Some(location) => writeln!(out, "{}{} 0x{:02x}:\t0x{:04x}\t{}\t; {}:{}: {}", marker, breakpoint, index, instruction, sloc, location.file, location.line, location.source),
+					None => writeln!(out, "{}{} 0x{:02x}:\t0x{:04x}\t{}", marker, breakpoint, index, instruction, sloc)
+				}
+			},
+			None => writeln!(out, "{}{} 0x{:02x}:\t<end of program>", marker, breakpoint, index)
+		}
+	}
+
+	fn print_registers<W: Write>(&self, out: &mut W) -> io::Result<()> {
+		for (index, value) in self.vm.context.registers.iter().enumerate() {
+			writeln!(out, "{}\t0x{:02x}\t{}", REGISTERS[index], value, value)?;
+		}
+		Ok(())
+	}
+
+	fn print_stack<W: Write>(&self, out: &mut W, radius: usize) -> io::Result<()> {
+		let stack = &self.vm.context.stack;
+		let rd: usize = self.vm.context.registers[RD as usize].into();
+		if stack.is_empty() {
+			return writeln!(out, "stack is empty, rd = 0x{:02x}", rd)
+		}
+		let start = rd.saturating_sub(radius);
+		for (index, value) in stack.iter().enumerate().skip(start).take(radius * 2) {
+			let marker = if index == rd { "<- rd" } else { "" };
+			let chr = char::from_u32((*value).into() as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+			writeln!(out, "0x{:02x}:\t0x{:02x}\t{:?}\t{}", index, value, chr, marker)?;
+		}
+		if rd >= stack.len() {
+			writeln!(out, "rd = 0x{:02x} (top of stack)", rd)?;
+		}
+		Ok(())
+	}
+
+	fn print_disassembly<W: Write>(&self, out: &mut W, radius: usize) -> io::Result<()> {
+		let pc = self.vm.pc();
+		let bytecode = &self.program.bytecode;
+		let mut starts = Vec::new();
+		let mut index = 0;
+		while let Some(&instruction) = bytecode.get(index) {
+			starts.push(index);
+			index += instruction_length(instruction, T::WIDE);
+		}
+		if pc >= bytecode.len() {
+			starts.push(pc);
+		}
+		let position = starts.iter().position(|&start| start >= pc).unwrap_or(starts.len());
+		for &index in starts.iter().skip(position.saturating_sub(radius)).take(radius * 2 + 1) {
+			self.print_instruction(out, index)?;
+		}
+		Ok(())
+	}
+
+	fn execute<R: BufRead, W: Write>(&mut self, steps: Option<usize>, input: &mut R, out: &mut W) -> io::Result<()> {
+		let mut remaining = steps;
+		loop {
+			let outcome = match remaining {
+				Some(0) => break,
+				Some(count) => { remaining = Some(count - 1); self.vm.step() },
+				None => {
+					let breakpoints = &self.breakpoints;
+					self.vm.run_until(|context| breakpoints.contains(&context.pc()))
+				}
+			};
+			match outcome {
+				StepOutcome::Continued => if remaining.is_none() {
+					writeln!(out, "Breakpoint at 0x{:02x}", self.vm.pc())?;
+					break
+				},
+				StepOutcome::WaitingForInput => {
+					write!(out, "program input> ")?;
+					out.flush()?;
+					let mut line = String::new();
+					if input.read_line(&mut line)? == 0 {
+						writeln!(out, "no input available")?;
+						break
+					}
+					self.vm.feed_input(line.as_bytes());
+					remaining = remaining.map(|count| count + 1);
+				},
+				StepOutcome::Stopped(stops) => {
+					for stop in stops {
+						writeln!(out, "{:?} triggered by 0x{:02x}: {} ({:?} -> {:?})", stop.watchpoint, stop.pc, disassemble_at(&self.program.bytecode, stop.pc, T::WIDE).unwrap_or_default(), stop.before, stop.after)?;
+					}
+					break
+				},
+				StepOutcome::Halted => { writeln!(out, "Program halted after {} steps", self.vm.steps())?; break },
+				StepOutcome::Faulted(error) => { writeln!(out, "Program faulted: {}", error)?; break }
+			}
+		}
+		self.print_instruction(out, self.vm.pc())
+	}
+
+	fn set<W: Write>(&mut self, args: &[&str], out: &mut W) -> io::Result<()> {
+		match args {
+			["stack", index, value] => match (parse_number(index), parse_number(value)) {
+				(Some(index), Some(value)) if T::from_usize(value).is_some() => {
+					if let Some(slot) = self.vm.context.stack.get_mut(index) {
+						*slot = T::from_usize(value).unwrap_or(T::MAX);
+						return Ok(())
+					}
+					writeln!(out, "stack slot 0x{:02x} does not exist", index)
+				},
+				_ => writeln!(out, "usage: set stack <index> <value>")
+			},
+			[register, value] => match (parse_register(register), parse_number(value)) {
+				(Some(register), Some(value)) if T::from_usize(value).is_some() => {
+					self.vm.context.registers[register as usize] = T::from_usize(value).unwrap_or(T::MAX);
+					if register == RN {
+						self.vm.context.bank = self.vm.context.registers[RB as usize];
+					}
+					Ok(())
+				},
+				(None, _) => writeln!(out, "unknown register {}", register),
+				_ => writeln!(out, "value must be between 0 and {}", T::MAX)
+			},
+			_ => writeln!(out, "usage: set <reg> <value> | set stack <index> <value>")
+		}
+	}
+
+	fn watch<W: Write>(&mut self, args: &[&str], out: &mut W) -> io::Result<()> {
+		let watchpoints = match args {
+			[] => {
+				for watchpoint in &self.vm.watchpoints {
+					writeln!(out, "{:?}", watchpoint)?;
+				}
+				return Ok(())
+			},
+			["stack", index] => match parse_number(index) {
+				Some(index) => vec![Watchpoint::StackRead(index), Watchpoint::StackWrite(index)],
+				None => return writeln!(out, "usage: watch stack <index>")
+			},
+			[register] => match parse_register(register) {
+				Some(register) => vec![Watchpoint::RegisterWrite(register)],
+				None => return writeln!(out, "unknown register {}", register)
+			},
+			[register, value] => match (parse_register(register), parse_number(value)) {
+				(Some(register), Some(value)) if T::from_usize(value).is_some() => vec![Watchpoint::RegisterValue(register, T::from_usize(value).unwrap_or(T::MAX))],
+				_ => return writeln!(out, "usage: watch <reg> [value]")
+			},
+			_ => return writeln!(out, "usage: watch <reg> [value] | watch stack <index>")
+		};
+		self.vm.watchpoints.extend(watchpoints);
+		Ok(())
+	}
+
+	pub fn command<R: BufRead, W: Write>(&mut self, line: &str, input: &mut R, out: &mut W) -> io::Result<bool> {
+		let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_owned() };
+		let tokens: Vec<&str> = line.split_whitespace().collect();
+		match tokens.as_slice() {
+			[] => {},
+			["s"] | ["step"] => self.execute(Some(1), input, out)?,
+			["s", count] | ["step", count] => match parse_number(count) {
+				Some(count) => self.execute(Some(count), input, out)?,
+				None => writeln!(out, "usage: step [n]")?
+			},
+			["c"] | ["continue"] => self.execute(None, input, out)?,
+			["b"] | ["break"] => for breakpoint in &self.breakpoints {
+				writeln!(out, "0x{:02x}\t{}", breakpoint, self.label_at(*breakpoint).unwrap_or(""))?;
+			},
+			["b", location] | ["break", location] => match self.resolve(location) {
+				Some(index) => if !self.breakpoints.contains(&index) { self.breakpoints.push(index) },
+				None => writeln!(out, "unknown location {}", location)?
+			},
+			["d", location] | ["delete", location] => match self.resolve(location) {
+				Some(index) => self.breakpoints.retain(|breakpoint| *breakpoint != index),
+				None => writeln!(out, "unknown location {}", location)?
+			},
+			["w", args @ ..] | ["watch", args @ ..] => self.watch(args, out)?,
+			["unwatch"] => self.vm.watchpoints.clear(),
+			["r"] | ["regs"] => self.print_registers(out)?,
+			["stack"] => self.print_stack(out, 8)?,
+			["stack", radius] => self.print_stack(out, parse_number(radius).unwrap_or(8))?,
+			["disas"] => self.print_disassembly(out, 4)?,
+			["disas", radius] => self.print_disassembly(out, parse_number(radius).unwrap_or(4))?,
+			["set", args @ ..] => self.set(args, out)?,
+			["restart"] => { self.restart(); self.print_instruction(out, self.vm.pc())? },
+			["q"] | ["quit"] => return Ok(false),
+			["h"] | ["help"] => writeln!(out, "{}", HELP)?,
+			_ => writeln!(out, "unknown command {}, try help", line)?
+		}
+		self.last_command = line;
+		Ok(true)
+	}
+
+	pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, mut out: W) -> io::Result<()> {
+		self.print_instruction(&mut out, self.vm.pc())?;
+		loop {
+			write!(out, "(rvm) ")?;
+			out.flush()?;
+			let mut line = String::new();
+			if input.read_line(&mut line)? == 0 || !self.command(&line, &mut input, &mut out)? {
+				return Ok(())
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::parser::assemble_str;
+
+	fn session(source: &str, script: &str) -> String {
+		let assembly = assemble_str(source, "test.rvm").unwrap();
+		let mut debugger: Debugger = Debugger::new(assembly.program(), assembly.labels);
+		let mut out = Vec::new();
+		debugger.repl(script.as_bytes(), &mut out).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn scripted_session() {
+		let output = session("\tset r1 2\n\tpush r1\ntarget:\tadd r0 r1\n\thlt\n", "break target\ncontinue\nstep\nset r0 5\nregs\nstack\ndisas 1\nrestart\nquit\n");
+		let registers: String = ["r0\t0x05\t5", "r1\t0x02\t2", "r2", "r3", "r4", "r5", "r6", "r7", "rn\t0x03\t3", "rd\t0x01\t1", "rf", "rc", "rs", "rb"].iter()
+			.map(|line| if line.len() == 2 { format!("{}\t0x00\t0\n", line) } else { format!("{}\n", line) })
+			.collect();
+		let expected = [
+			"=>  0x00:\t0x1102\tset r1 2\t; test.rvm:1: set r1 2\n",
+			"(rvm) (rvm) Breakpoint at 0x02\n",
+			"target:\n",
+			"=>* 0x02:\t0x4001\tadd r0 r1\t; test.rvm:3: target:\tadd r0 r1\n",
+			"(rvm) =>  0x03:\t0xfc0c\txor rs rs\t; test.rvm:4: hlt\n",
+			"(rvm) (rvm) ", &registers,
+			"(rvm) 0x00:\t0x02\t'\\u{2}'\t\n",
+			"rd = 0x01 (top of stack)\n",
+			"(rvm) target:\n",
+			"  * 0x02:\t0x4001\tadd r0 r1\t; test.rvm:3: target:\tadd r0 r1\n",
+			"=>  0x03:\t0xfc0c\txor rs rs\t; test.rvm:4: hlt\n",
+			"    0x04:\t0x0000\tint\t; test.rvm:4: hlt\n",
+			"(rvm) =>  0x00:\t0x1102\tset r1 2\t; test.rvm:1: set r1 2\n",
+			"(rvm) "
+		].concat();
+		assert_eq!(output, expected);
+	}
+
+	#[test]
+	fn empty_lines_repeat_and_halts_are_reported() {
+		let output = session("\tset r0 1\n\thlt\n", "step\n\n\nbreak nowhere\nfly\n");
+		let expected = [
+			"=>  0x00:\t0x1001\tset r0 1\t; test.rvm:1: set r0 1\n",
+			"(rvm) =>  0x01:\t0xfc0c\txor rs rs\t; test.rvm:2: hlt\n",
+			"(rvm) =>  0x02:\t0x0000\tint\t; test.rvm:2: hlt\n",
+			"(rvm) Program halted after 3 steps\n",
+			"=>  0x03:\t<end of program>\n",
+			"(rvm) unknown location nowhere\n",
+			"(rvm) unknown command fly, try help\n",
+			"(rvm) "
+		].concat();
+		assert_eq!(output, expected);
+	}
+}
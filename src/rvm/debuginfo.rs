@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+use super::*;
+
+pub const VERSION: u8 = 0x1;
+const NO_LABEL: u16 = u16::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+	pub file: String,
+	pub line: usize,
+	pub column: usize,
+	pub label: Option<String>,
+	pub source: String
+}
+
+impl fmt::Display for SourceLine {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}: {}", self.file, self.line, self.source)?;
+		if let Some(ref label) = self.label {
+			write!(f, " (in {})", label)?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DebugInfo {
+	pub lines: Vec<SourceLine>
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+	position: usize
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+		let bytes = self.bytes.get(self.position..self.position + length).ok_or(Error::FormatTruncatedError)?;
+		self.position += length;
+		Ok(bytes)
+	}
+
+	fn read_u16(&mut self) -> Result<u16, Error> {
+		let bytes = self.take(2)?;
+		Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+	}
+
+	fn read_u32(&mut self) -> Result<u32, Error> {
+		let bytes = self.take(4)?;
+		Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+}
+
+fn intern<'a>(strings: &mut Vec<&'a str>, indices: &mut HashMap<&'a str, u16>, string: &'a str) -> Result<u16, Error> {
+	if let Some(&index) = indices.get(string) {
+		return Ok(index)
+	}
+	if strings.len() >= NO_LABEL as usize || string.len() > u16::MAX as usize {
+		return Err(Error::FormatRangeError)
+	}
+	indices.insert(string, strings.len() as u16);
+	strings.push(string);
+	Ok(strings.len() as u16 - 1)
+}
+
+impl DebugInfo {
+	pub fn get(&self, pc: usize) -> Option<&SourceLine> {
+		self.lines.get(pc)
+	}
+
+	// version, string count (u16), strings (u16 length + utf-8), entry count (u16),
+	// one entry per instruction: file (u16 string), line (u32), column (u16), label (u16 string, 0xffff = none), source (u16 string)
+	pub fn encode(&self) -> Result<Vec<u8>, Error> {
+		let mut strings: Vec<&str> = Vec::new();
+		let mut indices: HashMap<&str, u16> = HashMap::new();
+		let mut entries = Vec::new();
+		for line in &self.lines {
+			let file = intern(&mut strings, &mut indices, &line.file)?;
+			let label = match line.label {
+				Some(ref label) => intern(&mut strings, &mut indices, label)?,
+				None => NO_LABEL
+			};
+			let source = intern(&mut strings, &mut indices, &line.source)?;
+			if line.line > u32::MAX as usize || line.column > u16::MAX as usize {
+				return Err(Error::FormatRangeError)
+			}
+			entries.push((file, line.line as u32, line.column as u16, label, source));
+		}
+		if entries.len() > u16::MAX as usize {
+			return Err(Error::FormatRangeError)
+		}
+
+		let mut buffer = vec![VERSION];
+		buffer.extend_from_slice(&(strings.len() as u16).to_be_bytes());
+		for string in strings {
+			buffer.extend_from_slice(&(string.len() as u16).to_be_bytes());
+			buffer.extend_from_slice(string.as_bytes());
+		}
+		buffer.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+		for (file, line, column, label, source) in entries {
+			buffer.extend_from_slice(&file.to_be_bytes());
+			buffer.extend_from_slice(&line.to_be_bytes());
+			buffer.extend_from_slice(&column.to_be_bytes());
+			buffer.extend_from_slice(&label.to_be_bytes());
+			buffer.extend_from_slice(&source.to_be_bytes());
+		}
+		Ok(buffer)
+	}
+
+	pub fn decode(bytes: &[u8]) -> Result<DebugInfo, Error> {
+		if bytes.is_empty() {
+			return Ok(DebugInfo::default())
+		}
+		let mut reader = Reader { bytes, position: 0 };
+		let version = reader.take(1)?[0];
+		if version != VERSION {
+			return Err(Error::FormatVersionError(version))
+		}
+
+		let count = reader.read_u16()?;
+		let mut strings = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			let length = reader.read_u16()?;
+			let string = String::from_utf8(reader.take(length as usize)?.to_vec()).map_err(|_| Error::FormatRangeError)?;
+			strings.push(string);
+		}
+		let string = |index: u16| strings.get(index as usize).cloned().ok_or(Error::FormatRangeError);
+
+		let count = reader.read_u16()?;
+		let mut lines = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			let file = string(reader.read_u16()?)?;
+			let line = reader.read_u32()? as usize;
+			let column = reader.read_u16()? as usize;
+			let label = match reader.read_u16()? {
+				NO_LABEL => None,
+				index => Some(string(index)?)
+			};
+			let source = string(reader.read_u16()?)?;
+			lines.push(SourceLine { file, line, column, label, source });
+		}
+		if reader.position != bytes.len() {
+			return Err(Error::FormatRangeError)
+		}
+		Ok(DebugInfo { lines })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample() -> DebugInfo {
+		let line = |file: &str, line, column, label: Option<&str>, source: &str| SourceLine { file: file.to_owned(), line, column, label: label.map(str::to_owned), source: source.to_owned() };
+		DebugInfo { lines: vec![
+			line("main.rvm", 1, 1, None, "set r0 1"),
+			line("main.rvm", 70000, 9, Some("loop"), "loop:\tadd r0 r1"),
+			line("lib/io.rvm", 3, 2, Some("loop"), "\tint")
+		] }
+	}
+
+	#[test]
+	fn encoding_round_trips() {
+		let info = sample();
+		let bytes = info.encode().unwrap();
+		assert_eq!(DebugInfo::decode(&bytes).unwrap(), info);
+		assert_eq!(DebugInfo::decode(&[]).unwrap(), DebugInfo::default());
+		assert_eq!(info.get(1).unwrap().to_string(), "main.rvm:70000: loop:\tadd r0 r1 (in loop)");
+	}
+
+	#[test]
+	fn truncated_input_is_rejected() {
+		let bytes = sample().encode().unwrap();
+		for length in 1..bytes.len() {
+			assert!(matches!(DebugInfo::decode(&bytes[..length]), Err(Error::FormatTruncatedError)), "{} bytes", length);
+		}
+	}
+
+	#[test]
+	fn corrupt_input_is_rejected() {
+		let bytes = sample().encode().unwrap();
+		let mut version = bytes.clone();
+		version[0] = 9;
+		assert!(matches!(DebugInfo::decode(&version), Err(Error::FormatVersionError(9))));
+
+		let mut trailing = bytes.clone();
+		trailing.push(0);
+		assert!(matches!(DebugInfo::decode(&trailing), Err(Error::FormatRangeError)));
+
+		// one string "a", one entry whose file index points past the string table
+		let dangling = [VERSION, 0, 1, 0, 1, b'a', 0, 1, 0, 5, 0, 0, 0, 1, 0, 1, 0xff, 0xff, 0, 0];
+		assert!(matches!(DebugInfo::decode(&dangling), Err(Error::FormatRangeError)));
+
+		let invalid_utf8 = [VERSION, 0, 1, 0, 1, 0xff, 0, 0];
+		assert!(matches!(DebugInfo::decode(&invalid_utf8), Err(Error::FormatRangeError)));
+	}
+}
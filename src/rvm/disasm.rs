@@ -0,0 +1,166 @@
+use super::*;
+use super::word::Word;
+use super::vm::{decode_interrupt, decode_opcode, decode_operands, decode_target, decode_value, decode_value_as_register, is_escape};
+
+pub fn disassemble_line(instruction: Instruction) -> Result<Sloc, VMError> {
+	let opcode = decode_opcode(&instruction)?;
+	if opcode == INT {
+		if decode_interrupt(&instruction)?.is_some() {
+			return Err(VMError::VMInvalidValueError)
+		}
+		return Ok(OPCODES[INT as usize].to_owned())
+	}
+
+	let target = decode_target(&instruction)?;
+	let value = if opcode == SET {
+		decode_value(&instruction).to_string()
+	} else if let Ok(register) = decode_value_as_register(&instruction) {
+		REGISTERS[register as usize].to_owned()
+	} else {
+		return Err(VMError::VMInvalidValueError)
+	};
+
+	Ok(format!("{} {} {}", OPCODES[opcode as usize], REGISTERS[target as usize], value))
+}
+
+fn is_extended_set(instruction: Instruction) -> bool {
+	matches!(decode_opcode(&instruction), Ok(SET)) && (instruction & 0x0F00) >> 8 == EXTENDED as Instruction
+}
+
+pub fn instruction_length(instruction: Instruction, wide: bool) -> usize {
+	if is_escape(instruction) || (wide && is_extended_set(instruction)) { 2 } else { 1 }
+}
+
+fn disassemble_secondary(instruction: Instruction, operands: Instruction) -> Result<Sloc, VMError> {
+	let secondary = decode_value(&instruction);
+	let (target, value) = decode_operands(secondary, operands)?;
+	let (name, arity) = SECONDARY.iter().find(|&&(_, number, _)| number == secondary).map(|&(name, _, arity)| (name, arity)).ok_or(VMError::VMInvalidOpcodeError)?;
+	Ok(match arity {
+		0 => name.to_owned(),
+		1 => format!("{} {}", name, REGISTERS[target as usize]),
+		_ if secondary & 0xF0 == IMMEDIATE => format!("{} {} {}", name, REGISTERS[target as usize], value),
+		_ => format!("{} {} {}", name, REGISTERS[target as usize], REGISTERS[value as usize])
+	})
+}
+
+pub fn disassemble_at(bytecode: &[Instruction], index: usize, wide: bool) -> Result<Sloc, VMError> {
+	let instruction = *bytecode.get(index).ok_or(VMError::VMContextFetchInvalidError { bank: index / BANK_SIZE, offset: index % BANK_SIZE })?;
+	if is_escape(instruction) {
+		let operands = bytecode.get(index + 1).ok_or(VMError::VMInvalidValueError)?;
+		return disassemble_secondary(instruction, *operands)
+	}
+	if !wide || !is_extended_set(instruction) {
+		return disassemble_line(instruction)
+	}
+	let target = decode_value_as_register(&instruction)?;
+	let immediate = bytecode.get(index + 1).ok_or(VMError::VMInvalidValueError)?;
+	Ok(format!("set.w {} {}", REGISTERS[target as usize], immediate))
+}
+
+pub fn disassemble(bytecode: &Bytecode) -> Result<Sloc, (usize, VMError)> {
+	disassemble_isa(bytecode, false)
+}
+
+pub fn disassemble_isa(bytecode: &Bytecode, wide: bool) -> Result<Sloc, (usize, VMError)> {
+	let sloc = if wide { ".wide\n".to_owned() } else { Sloc::new() };
+	disassemble_code(bytecode, wide, sloc)
+}
+
+pub fn disassemble_program(program: &rvb::Program) -> Result<Sloc, (usize, VMError)> {
+	let mut sloc = if program.is_wide() { ".wide\n".to_owned() } else { Sloc::new() };
+	let cells: Vec<usize> = if program.is_wide() {
+		u16::decode_stack(&program.stack).into_iter().map(|cell| cell as usize).collect()
+	} else {
+		program.stack.iter().map(|&cell| cell as usize).collect()
+	};
+	for chunk in cells.chunks(8) {
+		let values: Vec<String> = chunk.iter().map(|cell| cell.to_string()).collect();
+		sloc.push_str(&format!(".byte {}\n", values.join(", ")));
+	}
+	disassemble_code(&program.bytecode, program.is_wide(), sloc)
+}
+
+fn disassemble_code(bytecode: &Bytecode, wide: bool, mut sloc: Sloc) -> Result<Sloc, (usize, VMError)> {
+	let mut index = 0;
+	while let Some(&instruction) = bytecode.get(index) {
+		match disassemble_at(bytecode, index, wide) {
+			Ok(line) => { sloc.push_str(&line); sloc.push('\n') },
+			Err(error) => return Err((index, error))
+		}
+		index += instruction_length(instruction, wide);
+	}
+	Ok(sloc)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::parser::assemble_str;
+
+	fn round_trip(source: &str) {
+		round_trip_words(&assemble_str(source, "source.rvm").unwrap().bytecode);
+	}
+
+	fn round_trip_words(bytecode: &Bytecode) {
+		let sloc = disassemble(bytecode).unwrap();
+		assert_eq!(&assemble_str(&sloc, "disasm.rvm").unwrap().bytecode, bytecode, "{}", sloc);
+	}
+
+	#[test]
+	fn every_opcode_round_trips() {
+		let source = "int\nset r0 255\npsh r0 r7\npop rn rd\nadd r1 r2\nsub r3 r4\nmul r5 r6\ndiv r7 r0\nchk rf rc\ncns rn rs\nlpt r1 rd\nlsh r2 r3\nrsh r4 r5\nand r6 r7\nbor rb r0\nxor rs rs\n";
+		round_trip(source);
+		assert_eq!(disassemble(&assemble_str(source, "source.rvm").unwrap().bytecode).unwrap(), source);
+	}
+
+	#[test]
+	fn every_valid_word_round_trips() {
+		let bytecode: Bytecode = (0..=0xffff).filter(|&word| disassemble_line(word).is_ok()).collect();
+		assert_eq!(bytecode.len(), 6329);
+		round_trip_words(&bytecode);
+	}
+
+	#[test]
+	fn labels_and_pseudo_instructions_round_trip_as_plain_instructions() {
+		round_trip("start:\tset r1 1\nloop:\tadd r0 r1\n\tjlt r0 r1 loop\n\tmov r2 r0\n\tnop\n\tjmp start\n\thlt\n");
+	}
+
+	#[test]
+	fn invalid_words_are_reported_with_their_index() {
+		assert!(matches!(disassemble_line(0x0100), Err(VMError::VMInvalidTargetError)));
+		assert!(matches!(disassemble_line(0x4e00), Err(VMError::VMInvalidTargetError)));
+		assert!(matches!(disassemble_line(0x4010), Err(VMError::VMInvalidValueError)));
+		assert!(matches!(disassemble(&vec![0x1001, 0x4010]), Err((1, _))));
+	}
+
+	#[test]
+	fn data_images_round_trip_as_byte_directives() {
+		for source in &["msg: .asciz \"hi\"\n.fill 9, 7\n\tset r0 msg\n\thlt\n", ".wide\n.byte 1000, 65535, 3\n\tset r0 1\n"] {
+			let program = assemble_str(source, "source.rvm").unwrap().program();
+			let sloc = disassemble_program(&program).unwrap();
+			let again = assemble_str(&sloc, "disasm.rvm").unwrap().program();
+			assert_eq!((again.version, again.bytecode, again.stack), (program.version, program.bytecode, program.stack), "{}", sloc);
+		}
+		let program = assemble_str(".ascii \"ab\"\n\tint\n", "source.rvm").unwrap().program();
+		assert_eq!(disassemble_program(&program).unwrap(), ".byte 97, 98\nint\n");
+	}
+
+	#[test]
+	fn wide_programs_round_trip_with_set_w() {
+		let bytecode = assemble_str(".wide\n\tset r0 5\n\tset r1 1000\nloop:\tset r2 loop\n\tjmp loop\n", "source.rvm").unwrap().bytecode;
+		let sloc = disassemble_isa(&bytecode, true).unwrap();
+		assert!(sloc.starts_with(".wide\nset r0 5\nset.w r1 1000\nset.w r2 3\n"), "{}", sloc);
+		assert_eq!(assemble_str(&sloc, "disasm.rvm").unwrap().bytecode, bytecode, "{}", sloc);
+		assert!(matches!(disassemble_at(&bytecode, 1, false), Err(VMError::VMInvalidTargetError)));
+		assert!(matches!(disassemble_at(&[0x1f01], 0, true), Err(VMError::VMInvalidValueError)));
+	}
+
+	#[test]
+	fn secondary_instructions_round_trip() {
+		let source = "mod r1 r2\nnot r3\nspt r4 r5\ncal r6\nret\nadi r0 200\nsbi r1 1\nmli r2 3\ndvi r7 4\n";
+		round_trip(source);
+		assert_eq!(disassemble(&assemble_str(source, "source.rvm").unwrap().bytecode).unwrap(), source);
+		assert!(matches!(disassemble(&vec![0x1001, 0x0f01, 0x0301]), Err((1, VMError::VMInvalidValueError))));
+		assert!(matches!(disassemble(&vec![0x0f04]), Err((0, VMError::VMInvalidValueError))));
+	}
+}
@@ -0,0 +1,183 @@
+use super::*;
+use super::lexer::{columns, is_label, parse_literal, Columns, ParseResult, Token};
+
+const BINARY: [(&str, usize); 8] = [("|", 0), ("&", 1), ("<<", 2), (">>", 2), ("+", 3), ("-", 3), ("*", 4), ("/", 4)];
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+	Literal(i64, Columns),
+	Symbol(String, Columns),
+	Unary(&'static str, Box<Expression>, Columns),
+	Binary(&'static str, Box<Expression>, Box<Expression>, Columns)
+}
+
+struct ExpressionParser<'a, 'b> {
+	tokens: &'b [Token<'a>],
+	position: usize,
+	end: usize
+}
+
+impl<'a, 'b> ExpressionParser<'a, 'b> {
+	fn next(&mut self) -> ParseResult<Token<'a>> {
+		if let Some(token) = self.tokens.get(self.position) {
+			self.position += 1;
+			Ok(*token)
+		} else { Err((Error::ParseNoValueError(None), (self.end, self.end + 1))) }
+	}
+
+	fn binary(&mut self, precedence: usize) -> ParseResult<Expression> {
+		let mut left = self.unary()?;
+		while let Some(&(operator, level)) = self.tokens.get(self.position).and_then(|token| BINARY.iter().find(|&&(operator, _)| operator == token.1)) {
+			if level < precedence {
+				break
+			}
+			self.position += 1;
+			let right = self.binary(level + 1)?;
+			let span = (left.columns().0, right.columns().1);
+			left = Expression::Binary(operator, Box::new(left), Box::new(right), span);
+		}
+		Ok(left)
+	}
+
+	fn unary(&mut self) -> ParseResult<Expression> {
+		let token = self.next()?;
+		let operator = match token.1 {
+			"-" => "-",
+			"~" => "~",
+			"+" => "+",
+			"(" => {
+				let inner = self.binary(0)?;
+				return match self.tokens.get(self.position) {
+					Some(&(start, ")")) => { self.position += 1; Ok(inner.with_columns((token.0, start + 1))) },
+					Some(found) => Err((Error::ParseUnexpectedTokenError(found.1.to_owned()), columns(found))),
+					None => Err((Error::ParseUnclosedParenthesisError, columns(&token)))
+				}
+			},
+			_ => return self.primary(token)
+		};
+		let operand = self.unary()?;
+		let span = (token.0, operand.columns().1);
+		Ok(Expression::Unary(operator, Box::new(operand), span))
+	}
+
+	fn primary(&mut self, token: Token) -> ParseResult<Expression> {
+		match parse_literal(token.1) {
+			Some(Ok(value)) => Ok(Expression::Literal(value, columns(&token))),
+			Some(Err(error)) => Err((error, columns(&token))),
+			None if is_label(token.1) => Ok(Expression::Symbol(token.1.to_owned(), columns(&token))),
+			None => Err((Error::ParseNoValueError(Some(token.1.to_owned())), columns(&token)))
+		}
+	}
+}
+
+pub fn parse_expression(tokens: &[Token], end: usize) -> ParseResult<Expression> {
+	let mut parser = ExpressionParser { tokens, position: 0, end };
+	let expression = parser.binary(0)?;
+	if let Some(extra) = tokens.get(parser.position) {
+		return Err((Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra)))
+	}
+	Ok(expression)
+}
+
+impl Expression {
+	pub fn columns(&self) -> Columns {
+		match *self {
+			Expression::Literal(_, columns) | Expression::Symbol(_, columns) | Expression::Unary(_, _, columns) | Expression::Binary(_, _, _, columns) => columns
+		}
+	}
+
+	fn with_columns(self, columns: Columns) -> Expression {
+		match self {
+			Expression::Literal(value, _) => Expression::Literal(value, columns),
+			Expression::Symbol(name, _) => Expression::Symbol(name, columns),
+			Expression::Unary(operator, operand, _) => Expression::Unary(operator, operand, columns),
+			Expression::Binary(operator, left, right, _) => Expression::Binary(operator, left, right, columns)
+		}
+	}
+
+	pub fn symbols(&self) -> Vec<(&str, Columns)> {
+		match *self {
+			Expression::Literal(..) => Vec::new(),
+			Expression::Symbol(ref name, columns) => vec![(name.as_str(), columns)],
+			Expression::Unary(_, ref operand, _) => operand.symbols(),
+			Expression::Binary(_, ref left, ref right, _) => [left.symbols(), right.symbols()].concat()
+		}
+	}
+
+	pub fn evaluate<F>(&self, lookup: &mut F) -> ParseResult<Option<i64>> where F: FnMut(&str, Columns) -> ParseResult<Option<i64>> {
+		let overflow = |columns| (Error::ParseExpressionOverflowError, columns);
+		match *self {
+			Expression::Literal(value, _) => Ok(Some(value)),
+			Expression::Symbol(ref name, columns) => lookup(name, columns),
+			Expression::Unary(operator, ref operand, columns) => {
+				let value = if let Some(value) = operand.evaluate(lookup)? { value } else { return Ok(None) };
+				match operator {
+					"-" => value.checked_neg().ok_or_else(|| overflow(columns)).map(Some),
+					"~" => Ok(Some(!value)),
+					_ => Ok(Some(value))
+				}
+			},
+			Expression::Binary(operator, ref left, ref right, columns) => {
+				let (left, right) = match (left.evaluate(lookup)?, right.evaluate(lookup)?) {
+					(Some(left), Some(right)) => (left, right),
+					_ => return Ok(None)
+				};
+				let value = match operator {
+					"+" => left.checked_add(right),
+					"-" => left.checked_sub(right),
+					"*" => left.checked_mul(right),
+					"/" if right == 0 => return Err((Error::ParseDivisionByZeroError, columns)),
+					"/" => left.checked_div(right),
+					"<<" if (0..63).contains(&right) => left.checked_mul(1 << right),
+					">>" if (0..64).contains(&right) => Some(left >> right),
+					"&" => Some(left & right),
+					"|" => Some(left | right),
+					_ => None
+				};
+				value.ok_or_else(|| overflow(columns)).map(Some)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::lexer::tokenize;
+
+	fn evaluate(source: &str) -> ParseResult<Option<i64>> {
+		let tokens = tokenize(source).unwrap();
+		parse_expression(&tokens, source.len())?.evaluate(&mut |name, columns| match name {
+			"TEN" => Ok(Some(10)),
+			"LATER" => Ok(None),
+			_ => Err((Error::ParseUndefinedLabelError(name.to_owned()), columns))
+		})
+	}
+
+	#[test]
+	fn operators_follow_precedence() {
+		assert_eq!(evaluate("1 + 2 * 3").unwrap(), Some(7));
+		assert_eq!(evaluate("(1 + 2) * 3").unwrap(), Some(9));
+		assert_eq!(evaluate("1 << 2 + 1").unwrap(), Some(8));
+		assert_eq!(evaluate("0xf0 | 0x0f & 0x3").unwrap(), Some(0xf3));
+		assert_eq!(evaluate("10 - 4 - 3").unwrap(), Some(3));
+		assert_eq!(evaluate("-TEN / 3").unwrap(), Some(-3));
+		assert_eq!(evaluate("~0 & 0xff").unwrap(), Some(0xff));
+	}
+
+	#[test]
+	fn symbols_are_looked_up() {
+		assert_eq!(evaluate("TEN * TEN").unwrap(), Some(100));
+		assert_eq!(evaluate("TEN + LATER").unwrap(), None);
+		assert!(matches!(evaluate("1 + MISSING"), Err((Error::ParseUndefinedLabelError(_), (4, 11)))));
+	}
+
+	#[test]
+	fn errors_carry_the_span_of_the_operation() {
+		assert!(matches!(evaluate("1 + 4 / (2 - 2)"), Err((Error::ParseDivisionByZeroError, (4, 15)))));
+		assert!(matches!(evaluate("0x7fffffffffffffff + 1"), Err((Error::ParseExpressionOverflowError, _))));
+		assert!(matches!(evaluate("(1 + 2"), Err((Error::ParseUnclosedParenthesisError, (0, 1)))));
+		assert!(matches!(evaluate("1 +"), Err((Error::ParseNoValueError(None), _))));
+		assert!(matches!(evaluate("1 2"), Err((Error::ParseUnexpectedTokenError(_), (2, 3)))));
+	}
+}
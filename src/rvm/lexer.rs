@@ -0,0 +1,141 @@
+use std::str::Chars;
+use super::*;
+
+pub type Token<'a> = (usize, &'a str);
+pub type Columns = (usize, usize);
+pub type ParseResult<T> = Result<T, (Error, Columns)>;
+
+const OPERATORS: [&str; 12] = ["<<", ">>", "+", "-", "*", "/", "&", "|", "~", "(", ")", "="];
+
+pub fn columns(token: &Token) -> Columns {
+	(token.0, token.0 + token.1.len())
+}
+
+pub fn is_label(token: &str) -> bool {
+	let mut chars = token.chars();
+	match chars.next() {
+		Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+		_ => return false
+	}
+	chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '@')
+}
+
+fn is_separator(chr: char) -> bool {
+	chr.is_whitespace() || ",#;+-*/&|~()=<>".contains(chr)
+}
+
+pub fn tokenize(line: &str) -> ParseResult<Vec<Token<'_>>> {
+	let mut tokens = Vec::new();
+	let mut chars = line.char_indices().peekable();
+	while let Some(&(start, chr)) = chars.peek() {
+		if let Some(operator) = OPERATORS.iter().find(|operator| line[start..].starts_with(*operator)) {
+			tokens.push((start, &line[start..start + operator.len()]));
+			chars.nth(operator.len() - 1);
+			continue
+		}
+		match chr {
+			'#' | ';' => break,
+			',' | '<' | '>' => { tokens.push((start, &line[start..start + 1])); chars.next(); },
+			chr if chr.is_whitespace() => { chars.next(); },
+			_ => {
+				let mut end = line.len();
+				let mut quote = None;
+				while let Some(&(index, chr)) = chars.peek() {
+					match (quote, chr) {
+						(None, '\'') | (None, '"') => quote = Some(chr),
+						(Some(_), '\\') => { chars.next(); },
+						(Some(open), chr) if open == chr => quote = None,
+						(None, chr) if is_separator(chr) => { end = index; break },
+						_ => {}
+					}
+					chars.next();
+				}
+				if quote.is_some() {
+					return Err((Error::ParseUnterminatedQuoteError(line[start..end].to_owned()), (start, end)))
+				}
+				tokens.push((start, &line[start..end]));
+			}
+		}
+	}
+	Ok(tokens)
+}
+
+fn parse_escape(chars: &mut Chars) -> Option<i64> {
+	match chars.next()? {
+		'n' => Some('\n' as i64),
+		't' => Some('\t' as i64),
+		'r' => Some('\r' as i64),
+		'0' => Some(0),
+		'\\' => Some('\\' as i64),
+		'\'' => Some('\'' as i64),
+		'"' => Some('"' as i64),
+		'x' => {
+			let hex: String = chars.by_ref().take(2).collect();
+			i64::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 2)
+		},
+		_ => None
+	}
+}
+
+fn parse_char(literal: &str) -> Option<i64> {
+	let mut chars = literal.chars();
+	let value = match chars.next()? {
+		'\\' => parse_escape(&mut chars)?,
+		chr => chr as i64
+	};
+	if chars.next().is_some() { None } else { Some(value) }
+}
+
+pub fn parse_string(token: &str) -> Option<Result<Vec<Rsize>, Error>> {
+	let literal = token.strip_prefix('"')?.strip_suffix('"')?;
+	let mut bytes = Vec::new();
+	let mut chars = literal.chars();
+	while let Some(chr) = chars.next() {
+		let value = match chr {
+			'\\' => parse_escape(&mut chars),
+			chr => Some(chr as i64)
+		};
+		match value {
+			Some(value) if value <= Rsize::MAX as i64 => bytes.push(value as Rsize),
+			_ => return Some(Err(Error::ParseInvalidLiteralError(token.to_owned())))
+		}
+	}
+	Some(Ok(bytes))
+}
+
+pub fn parse_literal(token: &str) -> Option<Result<i64, Error>> {
+	let (negative, digits) = match token.strip_prefix('-') {
+		Some(digits) => (true, digits),
+		None => (false, token)
+	};
+	let value = if let Some(literal) = digits.strip_prefix('\'').and_then(|literal| literal.strip_suffix('\'')) {
+		parse_char(literal)
+	} else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+		let lowercase = digits.to_ascii_lowercase();
+		let (radix, number) = match lowercase.get(..2) {
+			Some("0x") => (16, &lowercase[2..]),
+			Some("0b") => (2, &lowercase[2..]),
+			Some("0o") => (8, &lowercase[2..]),
+			_ => (10, &lowercase[..])
+		};
+		i64::from_str_radix(number, radix).ok()
+	} else {
+		return None
+	};
+	Some(match value {
+		Some(value) if negative => Ok(-value),
+		Some(value) => Ok(value),
+		None => Err(Error::ParseInvalidLiteralError(token.to_owned()))
+	})
+}
+
+pub fn fit_immediate(value: i64, bits: u32) -> Result<u16, Error> {
+	let max = (1 << bits) - 1;
+	if value < 0 && value >= -(max / 2 + 1) {
+		Ok((value + max + 1) as u16)
+	} else if value >= 0 && value <= max {
+		Ok(value as u16)
+	} else {
+		Err(Error::ParseValueRangeError(value, bits))
+	}
+}
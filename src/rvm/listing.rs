@@ -0,0 +1,136 @@
+use std::io::{self, Write};
+use super::*;
+use super::disasm::{disassemble_at, instruction_length};
+use super::parser::{Assembly, Span};
+use super::vm::{decode_opcode, decode_target, decode_value, is_escape};
+
+fn position(span: &Span) -> String {
+	format!("{}:{}", span.file, span.line)
+}
+
+fn fields(instruction: Instruction) -> String {
+	let opcode = decode_opcode(&instruction).map(|opcode| OPCODES[opcode as usize]).unwrap_or("?");
+	let target = decode_target(&instruction).map(|target| REGISTERS[target as usize].to_owned()).unwrap_or_else(|_| format!("0x{:x}", (instruction >> 8) & 0xf));
+	format!("{}\t{}\t0x{:02x}", opcode, target, decode_value(&instruction))
+}
+
+pub fn write_listing<W: Write>(assembly: &Assembly, out: &mut W) -> io::Result<()> {
+	writeln!(out, "addr\tword\top\ttarget\tvalue\tinstruction\tsource")?;
+	let mut index = 0;
+	while let Some(instruction) = assembly.bytecode.get(index) {
+		let mut labels: Vec<&String> = assembly.labels.iter().filter(|&(_, address)| *address == index).map(|(label, _)| label).collect();
+		labels.sort();
+		for label in labels {
+			writeln!(out, "{}:", label)?;
+		}
+		let disassembly = disassemble_at(&assembly.bytecode, index, assembly.wide).unwrap_or_else(|_| "<invalid>".to_owned());
+		let source = match assembly.origins.get(index) {
+			Some(origin) => format!("{}\t{}", position(&origin.span), origin.source.trim()),
+			None => String::new()
+		};
+		let length = instruction_length(*instruction, assembly.wide);
+		if is_escape(*instruction) {
+			let operands = assembly.bytecode.get(index + 1).cloned().unwrap_or_default();
+			let name = SECONDARY.iter().find(|&&(_, number, _)| number == decode_value(instruction)).map(|&(name, _, _)| name).unwrap_or("?");
+			writeln!(out, "0x{:02x}\t0x{:04x}\tint\t{}\t0x{:02x}\t{}\t{}", index, instruction, name, decode_value(instruction), disassembly, source)?;
+			writeln!(out, "0x{:02x}\t0x{:04x}\t(operands)", index + 1, operands)?;
+		} else if length == 2 {
+			let immediate = assembly.bytecode.get(index + 1).cloned().unwrap_or_default();
+			writeln!(out, "0x{:02x}\t0x{:04x}\tset\t{}\t0x{:04x}\t{}\t{}", index, instruction, REGISTERS.get((*instruction & 0xff) as usize).unwrap_or(&"?"), immediate, disassembly, source)?;
+			writeln!(out, "0x{:02x}\t0x{:04x}\t(immediate)", index + 1, immediate)?;
+		} else {
+			writeln!(out, "0x{:02x}\t0x{:04x}\t{}\t{}\t{}", index, instruction, fields(*instruction), disassembly, source)?;
+		}
+		index += length;
+	}
+
+	if !assembly.data.is_empty() {
+		writeln!(out, "\ndata:")?;
+		let width = if assembly.wide { 4 } else { 2 };
+		for (row, chunk) in assembly.data.chunks(8).enumerate() {
+			let cells: Vec<String> = chunk.iter().map(|cell| format!("{:0width$x}", cell, width = width)).collect();
+			let text: String = chunk.iter().map(|&cell| if (0x20..0x7f).contains(&cell) { cell as u8 as char } else { '.' }).collect();
+			writeln!(out, "0x{:02x}\t{:<pad$}\t{}", row * 8, cells.join(" "), text, pad = 8 * (width + 1))?;
+		}
+	}
+
+	let mut labels: Vec<(&str, &String, usize)> = assembly.labels.iter().map(|(label, address)| ("code", label, *address))
+		.chain(assembly.data_labels.iter().map(|(label, address)| ("data", label, *address)))
+		.collect();
+	labels.sort_by(|a, b| (a.0, a.2, a.1).cmp(&(b.0, b.2, b.1)));
+	writeln!(out, "\nlabels:")?;
+	for (kind, label, address) in labels {
+		let defined = assembly.definitions.get(label).map(position).unwrap_or_default();
+		writeln!(out, "\t{}\t{}\t0x{:02x}\t{}", label, kind, address, defined)?;
+	}
+
+	let mut constants: Vec<(&String, &i64)> = assembly.constants.iter().collect();
+	constants.sort();
+	writeln!(out, "\nconstants:")?;
+	for (name, value) in constants {
+		let defined = assembly.definitions.get(name).map(position).unwrap_or_default();
+		writeln!(out, "\t{}\t{}\t0x{:x}\t{}", name, value, value, defined)?;
+	}
+
+	let mut names: Vec<&String> = assembly.definitions.keys().collect();
+	names.sort();
+	writeln!(out, "\ncross references:")?;
+	for name in names {
+		let mut uses: Vec<String> = Vec::new();
+		for use_position in assembly.references.get(name).into_iter().flatten().map(position) {
+			if !uses.contains(&use_position) {
+				uses.push(use_position);
+			}
+		}
+		let uses = if uses.is_empty() { "unused".to_owned() } else { uses.join(", ") };
+		writeln!(out, "\t{}\t{}\t{}", name, position(&assembly.definitions[name]), uses)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::parser::assemble_str;
+
+	#[test]
+	fn listing_shows_words_tables_and_references() {
+		let source = "\tljmp far\nCOUNT = 2\nmsg: .ascii \"hi\"\nstart:\tset r0 COUNT\n\tadi r0 1\n\tjmp start\n.bank 1\nfar:\tset r1 msg\n";
+		let assembly = assemble_str(source, "p.rvm").unwrap();
+		let mut out = Vec::new();
+		write_listing(&assembly, &mut out).unwrap();
+		let listing = String::from_utf8(out).unwrap();
+		let (padding, lines): (Vec<&str>, Vec<&str>) = listing.lines().partition(|line| line.ends_with("\t.bank 1"));
+		assert_eq!(padding.len(), 0x100 - 6);
+		assert_eq!(padding[0], "0x06\t0x0000\tint\tr0\t0x00\tint\tp.rvm:7\t.bank 1");
+		assert_eq!(lines, vec![
+			"addr\tword\top\ttarget\tvalue\tinstruction\tsource",
+			"0x00\t0x1d01\tset\trb\t0x01\tset rb 1\tp.rvm:1\tljmp far",
+			"0x01\t0x1800\tset\trn\t0x00\tset rn 0\tp.rvm:1\tljmp far",
+			"start:",
+			"0x02\t0x1002\tset\tr0\t0x02\tset r0 2\tp.rvm:4\tstart:\tset r0 COUNT",
+			"0x03\t0x0f10\tint\tadi\t0x10\tadi r0 1\tp.rvm:5\tadi r0 1",
+			"0x04\t0x0001\t(operands)",
+			"0x05\t0x1802\tset\trn\t0x02\tset rn 2\tp.rvm:6\tjmp start",
+			"far:",
+			"0x100\t0x1100\tset\tr1\t0x00\tset r1 0\tp.rvm:8\tfar:\tset r1 msg",
+			"",
+			"data:",
+			"0x00\t68 69                   \thi",
+			"",
+			"labels:",
+			"\tstart\tcode\t0x02\tp.rvm:4",
+			"\tfar\tcode\t0x100\tp.rvm:8",
+			"\tmsg\tdata\t0x00\tp.rvm:3",
+			"",
+			"constants:",
+			"\tCOUNT\t2\t0x2\tp.rvm:2",
+			"",
+			"cross references:",
+			"\tCOUNT\tp.rvm:2\tp.rvm:4",
+			"\tfar\tp.rvm:8\tp.rvm:1",
+			"\tmsg\tp.rvm:3\tp.rvm:8",
+			"\tstart\tp.rvm:4\tp.rvm:6"
+		]);
+	}
+}
@@ -1,22 +1,37 @@
+
+#[macro_use] extern crate log;
+
+use std::error;
+use std::fmt;
+use std::io;
+
 pub type Instruction = u16;
 pub type Sloc = String;
 pub type Bytecode = Vec<Instruction>;
 pub type Rsize = u8;
-pub type Stack = Vec<Rsize>;
-
-const R0: Rsize = 0x0;
-const R1: Rsize = 0x1;
-const R2: Rsize = 0x2;
-const R3: Rsize = 0x3;
-const R4: Rsize = 0x4;
-const R5: Rsize = 0x5;
-const R6: Rsize = 0x6;
-const R7: Rsize = 0x7;
-const RN: Rsize = 0x8;
-const RD: Rsize = 0x9;
-const RF: Rsize = 0xa;
-const RC: Rsize = 0xb;
-const RS: Rsize = 0xc;
+pub type Stack<W = Rsize> = Vec<W>;
+pub type Registers<W = Rsize> = [W; 14];
+
+pub const R0: Rsize = 0x0;
+pub const R1: Rsize = 0x1;
+pub const R2: Rsize = 0x2;
+pub const R3: Rsize = 0x3;
+pub const R4: Rsize = 0x4;
+pub const R5: Rsize = 0x5;
+pub const R6: Rsize = 0x6;
+pub const R7: Rsize = 0x7;
+pub const RN: Rsize = 0x8;
+pub const RD: Rsize = 0x9;
+pub const RF: Rsize = 0xa;
+pub const RC: Rsize = 0xb;
+pub const RS: Rsize = 0xc;
+pub const RB: Rsize = 0xd;
+
+pub const BANK_SIZE: usize = 0x100;
+
+const EXTENDED: Rsize = 0xf;
+
+const REGISTERS: [&str; 14] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "rn", "rd", "rf", "rc", "rs", "rb"];
 
 const INT: Rsize = 0x0;
 const SET: Rsize = 0x1;
@@ -35,26 +50,160 @@ const AND: Rsize = 0xd;
 const BOR: Rsize = 0xe;
 const XOR: Rsize = 0xf;
 
-const HALT: u8 = 0x0;
-const PRINTLINE: u8 = 0x1;
-const READLINE: u8 = 0x2;
+const OPCODES: [&str; 16] = ["int", "set", "psh", "pop", "add", "sub", "mul", "div", "chk", "cns", "lpt", "lsh", "rsh", "and", "bor", "xor"];
+
+const MOD: Rsize = 0x00;
+const NOT: Rsize = 0x01;
+const SPT: Rsize = 0x02;
+const CAL: Rsize = 0x03;
+const RET: Rsize = 0x04;
+const ADI: Rsize = 0x10;
+const SBI: Rsize = 0x11;
+const MLI: Rsize = 0x12;
+const DVI: Rsize = 0x13;
+
+// secondary opcodes behind the int escape: name, number, operand count
+// 0x00-0x0f register operations, 0x10-0x1f register and u8 immediate, 0x20-0xff reserved
+const SECONDARY: [(&str, Rsize, usize); 9] = [("mod", MOD, 2), ("not", NOT, 1), ("spt", SPT, 2), ("cal", CAL, 1), ("ret", RET, 0), ("adi", ADI, 2), ("sbi", SBI, 2), ("mli", MLI, 2), ("dvi", DVI, 2)];
+const IMMEDIATE: Rsize = 0x10;
+pub const EXTENSION_LEVEL: Rsize = 0x1;
+
+const PSEUDO: [&str; 9] = ["nop", "hlt", "mov", "jmp", "ljmp", "jeq", "jlt", "jgt", "push"];
+
+pub const HALT: Rsize = 0x0;
+pub const PRINTLINE: Rsize = 0x1;
+pub const READLINE: Rsize = 0x2;
+pub const ISAVERSION: Rsize = 0x3;
 
 const LE: u8 = 0x0;
 const EQ: u8 = 0x1;
 const GR: u8 = 0x2;
 
+#[derive(Debug)]
 pub enum Error {
-        ParseNoOpcodeError,
-        ParseNoTargetError,
-        ParseNoValueError,
+        ParseNoOpcodeError(String),
+        ParseNoTargetError(Option<String>),
+        ParseNoValueError(Option<String>),
+        ParseUnexpectedTokenError(String),
+        ParseUnterminatedQuoteError(String),
+        ParseInvalidLiteralError(String),
+        ParseValueRangeError(i64, u32),
+        ParseInvalidLabelError(String),
+        ParseReservedNameError(String),
+        ParseDuplicateLabelError(String),
+        ParseUndefinedLabelError(String),
+        ParseFarLabelError(String, usize, usize),
+        ParseBankError(usize, usize),
+        ParseInvalidConstantError(String),
+        ParseDuplicateConstantError(String),
+        ParseRecursiveConstantError(String),
+        ParseUnclosedParenthesisError,
+        ParseDivisionByZeroError,
+        ParseExpressionOverflowError,
+        ParseInvalidMacroError(String),
+        ParseDuplicateMacroError(String),
+        ParseNestedMacroError(String),
+        ParseUnterminatedMacroError(String),
+        ParseMacroArgumentsError(String, usize, usize),
+        ParseMacroDepthError(String, usize),
+        ParseOperandCountError(String, usize, usize),
+        ParseMovTargetError(String),
+        ParseInvalidIncludeError(String),
+        ParseIncludeError(String, io::Error),
+        ParseIncludeCycleError(String),
+        ParseNoStringError(String),
+        ParseDataRangeError(usize, usize),
+        ParseUnresolvedError(String),
+        ParseWideError,
+        ParseWideOnlyError(String),
+        ParseBankRangeError(i64),
+        FormatMagicError,
+        FormatVersionError(u8),
+        FormatTruncatedError,
+        FormatRangeError,
+        IoError(io::Error),
+        EncodingError,
         //ParseLineError,
         //ParseFileError,
 }
 
+const REGISTER_LIST: &str = "r0-r7, rn, rd, rf, rc, rs, rb";
+
+fn looks_like_register(token: &str) -> bool {
+	token.len() <= 3 && token.starts_with('r') && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::ParseNoOpcodeError(ref found) => write!(f, "unknown instruction `{}`, instructions are {}, extended instructions are {}, pseudo-instructions are {}", found, OPCODES.join(", "), SECONDARY.iter().map(|&(name, _, _)| name).collect::<Vec<_>>().join(", "), PSEUDO.join(", ")),
+			Error::ParseNoTargetError(None) => write!(f, "missing target register, registers are {}", REGISTER_LIST),
+			Error::ParseNoTargetError(Some(ref found)) if looks_like_register(found) => write!(f, "register `{}` does not exist, registers are {}", found, REGISTER_LIST),
+			Error::ParseNoTargetError(Some(ref found)) => write!(f, "expected a target register, found `{}`, registers are {}", found, REGISTER_LIST),
+			Error::ParseNoValueError(None) => write!(f, "missing value, expected a register (or an expression for `set`)"),
+			Error::ParseNoValueError(Some(ref found)) if looks_like_register(found) => write!(f, "register `{}` does not exist, registers are {}", found, REGISTER_LIST),
+			Error::ParseNoValueError(Some(ref found)) => write!(f, "expected a register (or an expression for `set`), found `{}`", found),
+			Error::ParseUnexpectedTokenError(ref found) => write!(f, "unexpected `{}`", found),
+			Error::ParseUnterminatedQuoteError(ref found) => write!(f, "unterminated quote in `{}`", found),
+			Error::ParseInvalidLiteralError(ref found) => write!(f, "invalid literal `{}`", found),
+			Error::ParseValueRangeError(value, bits) => write!(f, "value {} does not fit in {} bits", value, bits),
+			Error::ParseInvalidLabelError(ref label) => write!(f, "invalid label name `{}`", label),
+			Error::ParseReservedNameError(ref name) => write!(f, "`{}` contains `@`, which is reserved for labels generated by macro expansion", name),
+			Error::ParseDuplicateLabelError(ref label) => write!(f, "label `{}` is already defined", label),
+			Error::ParseUndefinedLabelError(ref label) => write!(f, "label or constant `{}` is not defined", label),
+			Error::ParseFarLabelError(ref label, bank, current) => write!(f, "label `{}` is in bank {} but used in bank {}, a bare label must be in the bank of the instruction using it and expressions see its full index (use `ljmp`, or `({}) >> 8` and `({}) & 0xff` for the far address)", label, bank, current, label, label),
+			Error::ParseBankError(bank, address) => write!(f, "bank {} starts at index 0x{:x}, but the code before it already reaches 0x{:x}", bank, bank * BANK_SIZE, address),
+			Error::ParseInvalidConstantError(ref name) if name.is_empty() => write!(f, "missing constant name after `.equ`"),
+			Error::ParseInvalidConstantError(ref name) => write!(f, "invalid constant name `{}`", name),
+			Error::ParseDuplicateConstantError(ref name) => write!(f, "`{}` is already defined as a label or constant", name),
+			Error::ParseRecursiveConstantError(ref name) => write!(f, "constant `{}` is defined in terms of itself", name),
+			Error::ParseUnclosedParenthesisError => write!(f, "unclosed parenthesis"),
+			Error::ParseDivisionByZeroError => write!(f, "division by zero"),
+			Error::ParseExpressionOverflowError => write!(f, "expression overflows while evaluating"),
+			Error::ParseInvalidMacroError(ref name) if name.is_empty() => write!(f, "missing macro name after `.macro`"),
+			Error::ParseInvalidMacroError(ref name) => write!(f, "invalid macro name or parameter `{}`", name),
+			Error::ParseDuplicateMacroError(ref name) => write!(f, "macro `{}` is already defined", name),
+			Error::ParseNestedMacroError(ref name) if name.is_empty() => write!(f, "`.macro` inside a macro definition, macros cannot be nested"),
+			Error::ParseNestedMacroError(ref name) => write!(f, "`.macro` inside the definition of macro `{}`, macros cannot be nested", name),
+			Error::ParseUnterminatedMacroError(ref name) => write!(f, "macro `{}` is missing `.endm`", name),
+			Error::ParseMacroArgumentsError(ref name, expected, found) => write!(f, "macro `{}` takes {} argument(s) but {} were given", name, expected, found),
+			Error::ParseMacroDepthError(ref name, depth) => write!(f, "expanding macro `{}` exceeds the nesting limit of {}", name, depth),
+			Error::ParseOperandCountError(ref name, expected, found) => write!(f, "`{}` takes {} operand(s) but {} were given", name, expected, found),
+			Error::ParseMovTargetError(ref register) => write!(f, "`mov` cannot copy a register into `{}`, the expansion writes its target twice; use `set {} value` or `cns {} rX`", register, register, register),
+			Error::ParseInvalidIncludeError(ref found) if found.is_empty() => write!(f, "missing quoted path after `.include`"),
+			Error::ParseInvalidIncludeError(ref found) => write!(f, "expected a quoted path after `.include`, found `{}`", found),
+			Error::ParseIncludeError(ref path, ref error) => write!(f, "cannot include `{}`: {}", path, error),
+			Error::ParseIncludeCycleError(ref chain) => write!(f, "include cycle: {}", chain),
+			Error::ParseNoStringError(ref found) => write!(f, "expected a quoted string, found `{}`", found),
+			Error::ParseUnresolvedError(ref operand) => write!(f, "{} must be resolvable at this point, it cannot depend on labels defined later", operand),
+			Error::ParseDataRangeError(length, max) => write!(f, "data image grows to {} cells, the stack holds at most {}", length, max),
+			Error::ParseWideError => write!(f, "`.wide` must come before any instruction or data"),
+			Error::ParseWideOnlyError(ref mnemonic) => write!(f, "`{}` needs wide mode, add `.wide` at the top of the program", mnemonic),
+			Error::ParseBankRangeError(bank) => write!(f, "bank {} is out of range, a program holds at most 0x{:x} instructions", bank, u16::MAX),
+			Error::FormatMagicError => write!(f, "not an rvm bytecode file (wrong magic)"),
+			Error::FormatVersionError(version) => write!(f, "unsupported ISA version {} (supported: {}, {} for wide mode)", version, rvb::VERSION, rvb::WIDE_VERSION),
+			Error::FormatTruncatedError => write!(f, "file is truncated"),
+			Error::FormatRangeError => write!(f, "entry point or section out of range"),
+			Error::IoError(ref error) => write!(f, "{}", error),
+			Error::EncodingError => write!(f, "invalid UTF-8 in source file")
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			Error::IoError(ref error) | Error::ParseIncludeError(_, ref error) => Some(error),
+			_ => None
+		}
+	}
+}
+
+#[derive(Debug)]
 pub enum VMError {
 	VMInterruptError,
-	VMContextFetchNextError,
-	VMContextFetchInvalidError,
+	VMContextFetchNextError { bank: usize, offset: usize },
+	VMContextFetchInvalidError { bank: usize, offset: usize },
 	VMHaltError,
 	VMInvalidOpcodeError,
 	VMInvalidTargetError,
@@ -62,9 +211,89 @@ pub enum VMError {
 	VMRegisterOverflowError,
 	VMStackOverflowError,
 	VMStackInvalidAccessError,
-	VMUnimplementedError
+	VMUnimplementedError(usize),
+	VMBudgetExhausted { steps: usize, pc: usize },
+	VMSyscallError(Box<dyn error::Error + Send + Sync>)
+}
+
+impl VMError {
+	// host errors are kept by their message
+	pub(crate) fn repeat(&self) -> VMError {
+		match *self {
+			VMError::VMInterruptError => VMError::VMInterruptError,
+			VMError::VMContextFetchNextError { bank, offset } => VMError::VMContextFetchNextError { bank, offset },
+			VMError::VMContextFetchInvalidError { bank, offset } => VMError::VMContextFetchInvalidError { bank, offset },
+			VMError::VMHaltError => VMError::VMHaltError,
+			VMError::VMInvalidOpcodeError => VMError::VMInvalidOpcodeError,
+			VMError::VMInvalidTargetError => VMError::VMInvalidTargetError,
+			VMError::VMInvalidValueError => VMError::VMInvalidValueError,
+			VMError::VMRegisterOverflowError => VMError::VMRegisterOverflowError,
+			VMError::VMStackOverflowError => VMError::VMStackOverflowError,
+			VMError::VMStackInvalidAccessError => VMError::VMStackInvalidAccessError,
+			VMError::VMUnimplementedError(number) => VMError::VMUnimplementedError(number),
+			VMError::VMBudgetExhausted { steps, pc } => VMError::VMBudgetExhausted { steps, pc },
+			VMError::VMSyscallError(ref error) => VMError::VMSyscallError(error.to_string().into())
+		}
+	}
+}
+
+impl fmt::Display for VMError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			VMError::VMContextFetchNextError { bank, offset } => write!(f, "Program counter ran past the last bank (bank {}, offset 0x{:x})", bank, offset),
+			VMError::VMContextFetchInvalidError { bank, offset } => write!(f, "No instruction found at bank {}, offset 0x{:x}", bank, offset),
+			VMError::VMUnimplementedError(number) => write!(f, "Syscall 0x{:x} not implemented (yet)", number),
+			VMError::VMInvalidOpcodeError => write!(f, "Invalid Opcode"),
+			VMError::VMInvalidTargetError => write!(f, "Invalid Target"),
+			VMError::VMInvalidValueError => write!(f, "Invalid Value"),
+			VMError::VMRegisterOverflowError => write!(f, "Register overflow / underflow"),
+			VMError::VMStackOverflowError => write!(f, "Stack overflow"),
+			VMError::VMStackInvalidAccessError => write!(f, "Invalid Stack access"),
+			VMError::VMInterruptError => write!(f, "Interrupt Error"),
+			VMError::VMSyscallError(ref error) => write!(f, "Syscall failed: {}", error),
+			VMError::VMBudgetExhausted { steps, pc } => write!(f, "Budget exhausted after {} steps at index 0x{:x}", steps, pc),
+			VMError::VMHaltError => write!(f, "Program halted")
+		}
+	}
+}
+
+impl error::Error for VMError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			VMError::VMSyscallError(ref error) => Some(error.as_ref()),
+			_ => None
+		}
+	}
 }
 
 
+pub mod debugger;
+pub mod debuginfo;
+pub mod disasm;
+mod expression;
+mod lexer;
+pub mod listing;
 pub mod parser;
+pub mod rvb;
+pub mod stream;
+pub mod syscall;
+pub mod trace;
 pub mod vm;
+pub mod word;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::error::Error as _;
+	use std::thread;
+
+	fn assert_send_sync<T: Send + Sync>() {}
+
+	#[test]
+	fn vm_errors_cross_threads() {
+		assert_send_sync::<VMError>();
+		let error = thread::spawn(|| VMError::VMSyscallError(Box::new(io::Error::other("disk full")))).join().unwrap();
+		assert_eq!(error.to_string(), "Syscall failed: disk full");
+		assert_eq!(error.source().map(|source| source.to_string()), Some("disk full".to_owned()));
+	}
+}
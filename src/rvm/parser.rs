@@ -1,144 +1,1351 @@
-use std::io::{BufReader,BufRead};
-use std::fs::File;
+use std::io::{self, BufReader, BufRead};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 
 use super::*;
+use super::lexer::{columns, fit_immediate, is_label, parse_string, tokenize, Columns, ParseResult, Token};
+use super::expression::{parse_expression, Expression};
+use super::disasm::disassemble_at;
+use super::debuginfo::{DebugInfo, SourceLine};
+
+#[derive(Default, Debug)]
+pub struct Assembly {
+	pub bytecode: Bytecode,
+	pub labels: HashMap<String, usize>,
+	pub constants: HashMap<String, i64>,
+	pub data: Stack<u16>,
+	pub data_labels: HashMap<String, usize>,
+	pub origins: Vec<Origin>,
+	pub definitions: HashMap<String, Span>,
+	pub references: HashMap<String, Vec<Span>>,
+	pub wide: bool
+}
+
+impl Assembly {
+	pub fn program(&self) -> rvb::Program {
+		let mut program = rvb::Program::new(self.bytecode.clone());
+		if self.wide {
+			program.version = rvb::WIDE_VERSION;
+			program.stack = self.data.iter().flat_map(|cell| cell.to_be_bytes().to_vec()).collect();
+		} else {
+			program.stack = self.data.iter().map(|&cell| cell as Rsize).collect();
+		}
+		match self.debug_info().encode() {
+			Ok(debug) => program.debug = debug,
+			Err(error) => warn!("Debug info omitted: {}", error)
+		}
+		program
+	}
+
+	pub fn debug_info(&self) -> DebugInfo {
+		let lines = self.origins.iter().enumerate().map(|(pc, origin)| {
+			let label = self.labels.iter()
+				.filter(|&(_, address)| *address <= pc)
+				.max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+				.map(|(label, _)| label.clone());
+			SourceLine { file: origin.span.file.clone(), line: origin.span.line, column: origin.span.columns.0 + 1, label, source: origin.source.trim().to_owned() }
+		}).collect();
+		DebugInfo { lines }
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+	pub file: String,
+	pub line: usize,
+	pub columns: (usize, usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct Origin {
+	pub span: Span,
+	pub source: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+	pub message: String,
+	pub span: Span,
+	pub source: String
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+	pub error: Error,
+	pub span: Span,
+	pub source: String,
+	pub notes: Vec<Note>
+}
+
+fn excerpt(f: &mut fmt::Formatter, span: &Span, source: &str) -> fmt::Result {
+	let gutter = span.line.to_string().len();
+	let (start, end) = span.columns;
+	let indent: String = source.chars().take(start).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+	if span.line == 0 {
+		return write!(f, "{:gutter$}--> {}", "", span.file, gutter = gutter)
+	}
+	writeln!(f, "{:gutter$}--> {}:{}:{}", "", span.file, span.line, start + 1, gutter = gutter)?;
+	writeln!(f, "{:gutter$} |", "", gutter = gutter)?;
+	writeln!(f, "{} | {}", span.line, source)?;
+	write!(f, "{:gutter$} | {}{}", "", indent, "^".repeat((end - start).max(1)), gutter = gutter)
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "error: {}", self.error)?;
+		excerpt(f, &self.span, &self.source)?;
+		let mut notes = self.notes.iter().peekable();
+		while let Some(note) = notes.next() {
+			let mut repeated = 1;
+			while notes.peek() == Some(&note) {
+				notes.next();
+				repeated += 1;
+			}
+			if repeated > 1 {
+				writeln!(f, "\nnote: {} ({} times)", note.message, repeated)?;
+			} else {
+				writeln!(f, "\nnote: {}", note.message)?;
+			}
+			excerpt(f, &note.span, &note.source)?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Default, Debug, Clone)]
+struct Location {
+	file: String,
+	linenumber: usize,
+	sloc: Sloc,
+	expansion: Vec<Note>,
+	invocation: Option<Span>
+}
+
+impl Location {
+	fn span(&self, columns: Columns) -> Span {
+		Span { file: self.file.clone(), line: self.linenumber, columns }
+	}
+
+	fn diagnostic(&self, error: Error, columns: Columns) -> Diagnostic {
+		Diagnostic { error, span: self.span(columns), source: self.sloc.clone(), notes: self.expansion.clone() }
+	}
+}
 
 #[derive(Default, Debug)]
 struct InstructionBuilder {
-	opcode_string: Option<String>,
 	opcode_hex: Option<Rsize>,
-	target_string: Option<String>,
 	target_hex: Option<Rsize>,
-	value_string: Option<String>,
+	value_expression: Option<Expression>,
 	value_hex: Option<Rsize>,
+	value_columns: Columns,
+	columns: Columns,
 	instruction: Instruction,
-	sloc: Sloc
+	extended: bool,
+	immediate: Option<u16>,
+	secondary: Option<Rsize>,
+	// words to the last instruction of the expansion, a bare label must be in the bank of that one
+	consumer: usize,
+	location: Location
 }
 
 impl InstructionBuilder {
+	fn length(&self) -> usize {
+		if self.extended || self.secondary.is_some() { 2 } else { 1 }
+	}
+
+	fn build_words(self) -> Result<Vec<Instruction>, Error> {
+		if let Some(secondary) = self.secondary {
+			let operands = (self.target_hex.unwrap_or(R0) as u16) << 8 | self.value_hex.unwrap_or(R0) as u16;
+			return Ok(vec![(INT as u16) << 12 | (EXTENDED as u16) << 8 | secondary as u16, operands])
+		}
+		if !self.extended {
+			return self.build_instruction().map(|instruction| vec![instruction])
+		}
+		let target = if let Some(target) = self.target_hex { target } else { return Err(Error::ParseNoTargetError(None)) };
+		let immediate = if let Some(immediate) = self.immediate.or(self.value_hex.map(|value| value as u16)) { immediate } else { return Err(Error::ParseNoValueError(None)) };
+		Ok(vec![(SET as u16) << 12 | (EXTENDED as u16) << 8 | target as u16, immediate])
+	}
+
 	fn build_instruction(mut self) -> Result<Instruction, Error> {
 		if let Some(opcode) = self.opcode_hex {
 			self.instruction = (opcode as u16) << 12;
-		} else { return Err(Error::ParseNoOpcodeError) }
+		} else { return Err(Error::ParseNoOpcodeError(String::new())) }
 
 		if let Some(target) = self.target_hex {
 			self.instruction += (target as u16) << 8;
 		} else { 
 			match self.opcode_hex {
 				Some(INT) => return Ok(self.instruction),
-				_ => return Err(Error::ParseNoTargetError)
+				_ => return Err(Error::ParseNoTargetError(None))
 			}
 		}
 
 		if let Some(value) = self.value_hex {
 			self.instruction += value as u16;
-		} else { return Err(Error::ParseNoValueError) }
+		} else { return Err(Error::ParseNoValueError(None)) }
 		
 		Ok(self.instruction)
 	}
+}
 
-	/*fn build_sloc(self) -> Result<Instruction, Error> {
-		unimplemented!()
-	}
-	
-}
-
-
-fn disassemble_line(line: Instruction) -> Sloc {
-	unimplemented!()
-*/}
-
-
-fn assemble_line(line: &str) -> Result<Instruction, Error> {
-	let tokens: Vec<&str> = line.split(" ").collect();
-	
-	let mut instruction_builder = InstructionBuilder::default();	
-
-	match tokens.get(0).map(|s| *s) {
-		Some("int") => { instruction_builder.opcode_hex = Some(INT); return instruction_builder.build_instruction() }, 
-		Some("set") => instruction_builder.opcode_hex = Some(SET), 
-		Some("psh") => instruction_builder.opcode_hex = Some(PSH), 
-		Some("pop") => instruction_builder.opcode_hex = Some(POP), 
-		Some("add") => instruction_builder.opcode_hex = Some(ADD), 
-		Some("sub") => instruction_builder.opcode_hex = Some(SUB), 
-		Some("mul") => instruction_builder.opcode_hex = Some(MUL), 
-		Some("div") => instruction_builder.opcode_hex = Some(DIV), 
-		Some("chk") => instruction_builder.opcode_hex = Some(CHK), 
-		Some("cns") => instruction_builder.opcode_hex = Some(CNS), 
-		Some("lpt") => instruction_builder.opcode_hex = Some(LPT), 
-		Some("lsh") => instruction_builder.opcode_hex = Some(LSH), 
-		Some("rsh") => instruction_builder.opcode_hex = Some(RSH), 
-		Some("and") => instruction_builder.opcode_hex = Some(AND), 
-		Some("bor") => instruction_builder.opcode_hex = Some(BOR), 
-		Some("xor") => instruction_builder.opcode_hex = Some(XOR), 
-		_ => return Err(Error::ParseNoOpcodeError)
-	}
-
-	match tokens.get(1).map(|s| *s) {
-		Some("r0") => instruction_builder.target_hex = Some(R0),
-		Some("r1") => instruction_builder.target_hex = Some(R1),
-		Some("r2") => instruction_builder.target_hex = Some(R2),
-		Some("r3") => instruction_builder.target_hex = Some(R3),
-		Some("r4") => instruction_builder.target_hex = Some(R4),
-		Some("r5") => instruction_builder.target_hex = Some(R5),
-		Some("r6") => instruction_builder.target_hex = Some(R6),
-		Some("r7") => instruction_builder.target_hex = Some(R7),
-		Some("rn") => instruction_builder.target_hex = Some(RN),
-		Some("rd") => instruction_builder.target_hex = Some(RD),
-		Some("rf") => instruction_builder.target_hex = Some(RF),
-		Some("rc") => instruction_builder.target_hex = Some(RC),
-		Some("rs") => instruction_builder.target_hex = Some(RS),
-		_ => return Err(Error::ParseNoTargetError)
-	}
-	
-	match tokens.get(2).map(|s| *s) {
-		Some("r0") => instruction_builder.value_hex = Some(R0),
-		Some("r1") => instruction_builder.value_hex = Some(R1),
-		Some("r2") => instruction_builder.value_hex = Some(R2),
-		Some("r3") => instruction_builder.value_hex = Some(R3),
-		Some("r4") => instruction_builder.value_hex = Some(R4),
-		Some("r5") => instruction_builder.value_hex = Some(R5),
-		Some("r6") => instruction_builder.value_hex = Some(R6),
-		Some("r7") => instruction_builder.value_hex = Some(R7),
-		Some("rn") => instruction_builder.value_hex = Some(RN),
-		Some("rd") => instruction_builder.value_hex = Some(RD),
-		Some("rf") => instruction_builder.value_hex = Some(RF),
-		Some("rc") => instruction_builder.value_hex = Some(RC),
-		Some("rs") => instruction_builder.value_hex = Some(RS),
-		Some(x) => {
-			let value = x.parse::<Rsize>();
-			if instruction_builder.opcode_hex == Some(SET) {
-				instruction_builder.value_hex = value.ok();
-			} else { 
-				return Err(Error::ParseNoValueError)
-			};
+const BUILTINS: [(&str, Rsize); 4] = [("HALT", HALT), ("PRINTLINE", PRINTLINE), ("READLINE", READLINE), ("ISAVERSION", ISAVERSION)];
+
+struct Constant {
+	expression: Expression,
+	location: Location
+}
+
+struct Symbols<'a> {
+	labels: &'a HashMap<String, usize>,
+	data_labels: &'a HashMap<String, usize>,
+	constants: &'a HashMap<String, Constant>,
+	values: HashMap<String, Option<i64>>,
+	pending: Vec<String>,
+	diagnostics: Vec<Diagnostic>
+}
+
+impl<'a> Symbols<'a> {
+	fn lookup(&mut self, name: &str, columns: Columns) -> ParseResult<Option<i64>> {
+		if self.constants.contains_key(name) {
+			return self.constant(name, columns)
+		}
+		if let Some(address) = self.labels.get(name).or_else(|| self.data_labels.get(name)) {
+			return Ok(Some(*address as i64))
+		}
+		if let Some(&(_, value)) = BUILTINS.iter().find(|&&(builtin, _)| builtin == name) {
+			return Ok(Some(value as i64))
+		}
+		Err((Error::ParseUndefinedLabelError(name.to_owned()), columns))
+	}
+
+	fn constant(&mut self, name: &str, columns: Columns) -> ParseResult<Option<i64>> {
+		if let Some(value) = self.values.get(name) {
+			return Ok(*value)
+		}
+		if self.pending.iter().any(|pending| pending == name) {
+			return Err((Error::ParseRecursiveConstantError(name.to_owned()), columns))
+		}
+		let constants = self.constants;
+		let constant = &constants[name];
+		self.pending.push(name.to_owned());
+		let value = match constant.expression.evaluate(&mut |name, columns| self.lookup(name, columns)) {
+			Ok(value) => value,
+			Err((error, columns)) => {
+				self.diagnostics.push(constant.location.diagnostic(error, columns));
+				None
+			}
+		};
+		self.pending.pop();
+		self.values.insert(name.to_owned(), value);
+		Ok(value)
+	}
+
+	fn immediate(&mut self, expression: &Expression, bits: u32, bank_size: usize, index: usize) -> ParseResult<Option<u16>> {
+		if let Expression::Symbol(ref label, columns) = *expression {
+			if let Some(&address) = self.labels.get(label) {
+				if address / bank_size != index / bank_size {
+					return Err((Error::ParseFarLabelError(label.to_owned(), address / bank_size, index / bank_size), columns))
+				}
+				return fit_immediate((address % bank_size) as i64, bits).map(Some).map_err(|error| (error, columns))
+			}
+		}
+		match self.evaluate(expression)? {
+			Some(value) => fit_immediate(value, bits).map(Some).map_err(|error| (error, expression.columns())),
+			None => Ok(None)
+		}
+	}
+
+	fn evaluate(&mut self, expression: &Expression) -> ParseResult<Option<i64>> {
+		expression.evaluate(&mut |name, columns| self.lookup(name, columns))
+	}
+}
+
+fn split_label<'a, 'b>(tokens: &'b [Token<'a>]) -> ParseResult<(Option<Token<'a>>, &'b [Token<'a>])> {
+	if let Some(&(offset, first)) = tokens.first() {
+		if let Some(label) = first.strip_suffix(':') {
+			if !is_label(label) || parse_register(label).is_some() {
+				return Err((Error::ParseInvalidLabelError(label.to_owned()), (offset, offset + label.len())))
+			}
+			return Ok((Some((offset, label)), &tokens[1..]))
+		}
+	}
+	Ok((None, tokens))
+}
+
+fn parse_register(token: &str) -> Option<Rsize> {
+	match token.to_ascii_lowercase().as_str() {
+		"r0" => Some(R0),
+		"r1" => Some(R1),
+		"r2" => Some(R2),
+		"r3" => Some(R3),
+		"r4" => Some(R4),
+		"r5" => Some(R5),
+		"r6" => Some(R6),
+		"r7" => Some(R7),
+		"rn" => Some(RN),
+		"rd" => Some(RD),
+		"rf" => Some(RF),
+		"rc" => Some(RC),
+		"rs" => Some(RS),
+		"rb" => Some(RB),
+		_ => None
+	}
+}
+
+fn parse_line(tokens: &[Token], end: usize) -> ParseResult<InstructionBuilder> {
+	let mut instruction_builder = InstructionBuilder::default();
+
+	let opcode = tokens[0];
+	let tokens = &tokens[1..];
+	let mnemonic = opcode.1.to_ascii_lowercase();
+	if let Some(&(_, secondary, arity)) = SECONDARY.iter().find(|&&(name, _, _)| name == mnemonic) {
+		instruction_builder.opcode_hex = Some(INT);
+		instruction_builder.secondary = Some(secondary);
+		if arity < 2 {
+			if tokens.len() != arity {
+				return Err((Error::ParseOperandCountError(mnemonic, arity, tokens.len()), (opcode.0, end)))
+			}
+			if let Some(target) = tokens.first() {
+				match parse_register(target.1) {
+					Some(register) => instruction_builder.target_hex = Some(register),
+					None => return Err((Error::ParseNoTargetError(Some(target.1.to_owned())), columns(target)))
+				}
+			}
+			return Ok(instruction_builder)
+		}
+	}
+	match mnemonic.as_str() {
+		"int" => {
+			if let Some(extra) = tokens.first() {
+				return Err((Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra)))
+			}
+			instruction_builder.opcode_hex = Some(INT);
+			return Ok(instruction_builder)
+		}, 
+		"set" => instruction_builder.opcode_hex = Some(SET), 
+		"set.w" => { instruction_builder.opcode_hex = Some(SET); instruction_builder.extended = true }, 
+		"psh" => instruction_builder.opcode_hex = Some(PSH), 
+		"pop" => instruction_builder.opcode_hex = Some(POP), 
+		"add" => instruction_builder.opcode_hex = Some(ADD), 
+		"sub" => instruction_builder.opcode_hex = Some(SUB), 
+		"mul" => instruction_builder.opcode_hex = Some(MUL), 
+		"div" => instruction_builder.opcode_hex = Some(DIV), 
+		"chk" => instruction_builder.opcode_hex = Some(CHK), 
+		"cns" => instruction_builder.opcode_hex = Some(CNS), 
+		"lpt" => instruction_builder.opcode_hex = Some(LPT), 
+		"lsh" => instruction_builder.opcode_hex = Some(LSH), 
+		"rsh" => instruction_builder.opcode_hex = Some(RSH), 
+		"and" => instruction_builder.opcode_hex = Some(AND), 
+		"bor" => instruction_builder.opcode_hex = Some(BOR), 
+		"xor" => instruction_builder.opcode_hex = Some(XOR), 
+		_ if instruction_builder.secondary.is_some() => {},
+		_ => return Err((Error::ParseNoOpcodeError(opcode.1.to_owned()), columns(&opcode)))
+	}
+
+	let tokens = match tokens.split_first() {
+		Some((target, rest)) => match parse_register(target.1) {
+			Some(register) => { instruction_builder.target_hex = Some(register); rest },
+			None => return Err((Error::ParseNoTargetError(Some(target.1.to_owned())), columns(target)))
+		},
+		None => return Err((Error::ParseNoTargetError(None), (end, end + 1)))
+	};
+	let tokens = match tokens {
+		[(_, ","), rest @ ..] => rest,
+		_ => tokens
+	};
+
+	match tokens {
+		[] => return Err((Error::ParseNoValueError(None), (end, end + 1))),
+		[comma, ..] if comma.1 == "," => return Err((Error::ParseUnexpectedTokenError(",".to_owned()), columns(comma))),
+		[value] if parse_register(value.1).is_some() => {
+			instruction_builder.value_columns = columns(value);
+			instruction_builder.value_hex = parse_register(value.1);
+		},
+		[value, extra, ..] if parse_register(value.1).is_some() => return Err((Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra))),
+		_ if instruction_builder.opcode_hex == Some(SET) || instruction_builder.secondary.is_some_and(|secondary| secondary & 0xF0 == IMMEDIATE) => {
+			let expression = parse_expression(tokens, end)?;
+			instruction_builder.value_columns = expression.columns();
+			instruction_builder.value_expression = Some(expression);
 		},
-		_ => {}
+		[value, ..] => return Err((Error::ParseNoValueError(Some(value.1.to_owned())), columns(value)))
+	}
+
+	Ok(instruction_builder)
+}
+
+fn split_constant<'a, 'b>(tokens: &'b [Token<'a>], end: usize) -> ParseResult<Option<(Token<'a>, &'b [Token<'a>])>> {
+	let (name, expression) = match tokens {
+		[directive, rest @ ..] if directive.1.eq_ignore_ascii_case(".equ") => match rest {
+			[name, (_, ","), expression @ ..] | [name, expression @ ..] => (*name, expression),
+			[] => return Err((Error::ParseInvalidConstantError(String::new()), (end, end + 1)))
+		},
+		[name, (_, "="), expression @ ..] => (*name, expression),
+		_ => return Ok(None)
+	};
+	if !is_label(name.1) || parse_register(name.1).is_some() {
+		return Err((Error::ParseInvalidConstantError(name.1.to_owned()), columns(&name)))
+	}
+	Ok(Some((name, expression)))
+}
+
+const SCRATCH: &str = "r7";
+
+fn pseudo<'a>(tokens: &[Token<'a>], end: usize, wide: bool) -> ParseResult<Option<Vec<Vec<Token<'a>>>>> {
+	let mnemonic = tokens[0];
+	let name = mnemonic.1.to_ascii_lowercase();
+	let operands = split_arguments(&tokens[1..])?;
+	let arity = match name.as_str() {
+		"nop" | "hlt" => 0,
+		"jmp" | "ljmp" | "push" => 1,
+		"psh" | "pop" if operands.len() == 1 => 1,
+		"mov" => 2,
+		"jeq" | "jlt" | "jgt" => 3,
+		_ => return Ok(None)
+	};
+	if operands.len() != arity {
+		return Err((Error::ParseOperandCountError(name, arity, operands.len()), (mnemonic.0, end)))
+	}
+	let at = |token: &'static str| vec![(mnemonic.0, token)];
+	let operand = |index: usize| operands[index].to_vec();
+	let lines = match name.as_str() {
+		"nop" => vec![[at("bor"), at("r0"), at("r0")].concat()],
+		"hlt" => vec![[at("xor"), at("rs"), at("rs")].concat(), at("int")],
+		"jmp" => vec![[at("set"), at("rn"), operand(0)].concat()],
+		"ljmp" if wide => vec![[at("set"), at("rb"), at("0")].concat(), [at("set"), at("rn"), operand(0)].concat()],
+		"ljmp" => vec![
+			[at("set"), at("rb"), at("("), operand(0), at(")"), at(">>"), at("8")].concat(),
+			[at("set"), at("rn"), at("("), operand(0), at(")"), at("&"), at("0xff")].concat()
+		],
+		"push" | "psh" => vec![[at("psh"), operand(0), operand(0)].concat()],
+		"pop" => vec![[at("pop"), operand(0), operand(0)].concat()],
+		"mov" => match (operands[0], operands[1]) {
+			([target], [value]) if parse_register(value.1).is_some() && parse_register(target.1) == parse_register(value.1) => vec![[at("bor"), operand(0), operand(1)].concat()],
+			([target], [value]) if parse_register(value.1).is_some() && parse_register(target.1).is_some_and(|register| register > R7) => {
+				return Err((Error::ParseMovTargetError(target.1.to_ascii_lowercase()), columns(target)))
+			},
+			([_], [value]) if parse_register(value.1).is_some() => vec![[at("xor"), operand(0), operand(0)].concat(), [at("bor"), operand(0), operand(1)].concat()],
+			_ => vec![[at("set"), operand(0), operand(1)].concat()]
+		},
+		_ => {
+			let condition = match name.as_str() { "jeq" => "1", "jlt" => "0", _ => "2" };
+			vec![
+				[at("chk"), operand(0), operand(1)].concat(),
+				[at("set"), at("rc"), at(condition)].concat(),
+				[at("set"), at(SCRATCH), operand(2)].concat(),
+				[at("cns"), at("rn"), at(SCRATCH)].concat()
+			]
+		}
+	};
+	Ok(Some(lines))
+}
+
+const MACRO_DEPTH: usize = 16;
+
+struct Macro {
+	name: String,
+	columns: Columns,
+	location: Location,
+	parameters: Vec<String>,
+	locals: Vec<String>,
+	body: Vec<Location>
+}
+
+fn parse_macro_header<'a>(tokens: &[Token<'a>], end: usize) -> ParseResult<(Token<'a>, Vec<String>)> {
+	let name = match tokens.get(1) {
+		Some(name) => name,
+		None => return Err((Error::ParseInvalidMacroError(String::new()), (end, end + 1)))
+	};
+	let reserved = |token: &str| !is_label(token) || parse_register(token).is_some() || OPCODES.contains(&token.to_ascii_lowercase().as_str()) || PSEUDO.contains(&token.to_ascii_lowercase().as_str())
+		|| SECONDARY.iter().any(|&(name, _, _)| token.eq_ignore_ascii_case(name));
+	if reserved(name.1) {
+		return Err((Error::ParseInvalidMacroError(name.1.to_owned()), columns(name)))
+	}
+	let mut parameters: Vec<String> = Vec::new();
+	for parameter in split_arguments(&tokens[2..])? {
+		match *parameter {
+			[(_, name)] if !reserved(name) && !parameters.iter().any(|parameter| parameter == name) => parameters.push(name.to_owned()),
+			[ref token, ..] => return Err((Error::ParseInvalidMacroError(token.1.to_owned()), columns(token))),
+			[] => {}
+		}
+	}
+	Ok((*name, parameters))
+}
+
+fn split_arguments<'a, 'b>(tokens: &'b [Token<'a>]) -> ParseResult<Vec<&'b [Token<'a>]>> {
+	if !tokens.iter().any(|token| token.1 == ",") {
+		return Ok(tokens.chunks(1).collect())
+	}
+	let mut arguments = Vec::new();
+	let mut start = 0;
+	for (index, token) in tokens.iter().enumerate() {
+		if token.1 != "," {
+			continue
+		}
+		if index == start || index + 1 == tokens.len() {
+			return Err((Error::ParseUnexpectedTokenError(",".to_owned()), columns(token)))
+		}
+		arguments.push(&tokens[start..index]);
+		start = index + 1;
+	}
+	arguments.push(&tokens[start..]);
+	Ok(arguments)
+}
+
+pub fn assemble_file(path: &str) -> Result<Assembly, Vec<Diagnostic>> {
+	match File::open(path) {
+		Ok(file) => assemble_reader(BufReader::new(file), path),
+		Err(error) => Err(vec![Diagnostic { error: Error::IoError(error), span: Span { file: path.to_owned(), line: 0, columns: (0, 0) }, source: String::new(), notes: Vec::new() }])
+	}
+}
+
+pub fn assemble_str(source: &str, name: &str) -> Result<Assembly, Vec<Diagnostic>> {
+	assemble_reader(source.as_bytes(), name)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Result<String, (String, usize)>>> {
+	let mut buffer = Vec::new();
+	if reader.read_until(b'\n', &mut buffer)? == 0 {
+		return Ok(None)
+	}
+	if buffer.ends_with(b"\n") { buffer.pop(); }
+	if buffer.ends_with(b"\r") { buffer.pop(); }
+	match String::from_utf8(buffer) {
+		Ok(line) => Ok(Some(Ok(line))),
+		Err(error) => {
+			let column = error.utf8_error().valid_up_to();
+			Ok(Some(Err((String::from_utf8_lossy(error.as_bytes()).into_owned(), column))))
+		}
 	}
+}
+
+const DATA: [&str; 4] = [".byte", ".ascii", ".asciz", ".fill"];
 
-	instruction_builder.build_instruction()
+struct Fixup {
+	index: usize,
+	count: usize,
+	expression: Expression,
+	location: Location
 }
 
-pub fn assemble_file(path: &str) -> Result<Bytecode, Error> {
-	let mut bytecode: Bytecode = Vec::new();
+#[derive(Default)]
+struct Assembler {
+	builders: Vec<InstructionBuilder>,
+	words: usize,
+	wide: bool,
+	labels: HashMap<String, usize>,
+	constants: HashMap<String, Constant>,
+	constant_names: Vec<String>,
+	macros: HashMap<String, Rc<Macro>>,
+	expansions: usize,
+	macro_overflow: bool,
+	data: Stack<u16>,
+	data_labels: HashMap<String, usize>,
+	fixups: Vec<Fixup>,
+	pending_labels: Vec<String>,
+	definitions: HashMap<String, Span>,
+	references: HashMap<String, Vec<Span>>,
+	files: Vec<String>,
+	included: HashSet<PathBuf>,
+	includes: Vec<(PathBuf, String)>,
+	diagnostics: Vec<Diagnostic>
+}
+
+impl Assembler {
+	fn error(&mut self, location: &Location, (error, columns): (Error, Columns)) {
+		self.diagnostics.push(location.diagnostic(error, columns));
+	}
+
+	fn is_label(&self, name: &str) -> bool {
+		self.labels.contains_key(name) || self.data_labels.contains_key(name) || self.pending_labels.iter().any(|label| label == name)
+	}
+
+	fn is_defined(&self, name: &str) -> bool {
+		self.is_label(name) || self.constants.contains_key(name) || BUILTINS.iter().any(|&(builtin, _)| builtin == name)
+	}
+
+	fn bits(&self) -> u32 {
+		if self.wide { 16 } else { 8 }
+	}
+
+	fn bank_size(&self) -> usize {
+		if self.wide { u16::MAX as usize + 1 } else { BANK_SIZE }
+	}
+
+	fn push(&mut self, instruction_builder: InstructionBuilder) {
+		self.words += instruction_builder.length();
+		self.builders.push(instruction_builder);
+	}
+
+	fn bind_labels(&mut self, data: bool) {
+		for label in self.pending_labels.drain(..) {
+			if data {
+				self.data_labels.insert(label, self.data.len());
+			} else {
+				self.labels.insert(label, self.words);
+			}
+		}
+	}
+
+	fn reference(&mut self, expression: &Expression, location: &Location) {
+		for (name, columns) in expression.symbols() {
+			let span = location.invocation.clone().unwrap_or_else(|| location.span(columns));
+			self.references.entry(name.to_owned()).or_default().push(span);
+		}
+	}
+
+	fn symbols(&self) -> Symbols<'_> {
+		Symbols { labels: &self.labels, data_labels: &self.data_labels, constants: &self.constants, values: HashMap::new(), pending: Vec::new(), diagnostics: Vec::new() }
+	}
+
+	fn data(&mut self, tokens: &[Token], location: &Location, end: usize) {
+		let directive = tokens[0].1.to_ascii_lowercase();
+		let operands = match split_arguments(&tokens[1..]) {
+			Ok(operands) => operands,
+			Err(error) => return self.error(location, error)
+		};
+		if operands.is_empty() {
+			return self.error(location, (Error::ParseNoValueError(None), (end, end + 1)))
+		}
+		self.bind_labels(true);
+		let start = self.data.len();
+		match directive.as_str() {
+			".ascii" | ".asciz" => {
+				for operand in operands {
+					let token = operand[0];
+					match parse_string(token.1) {
+						Some(Ok(bytes)) if operand.len() == 1 => self.data.extend(bytes.iter().map(|&byte| byte as u16)),
+						Some(Err(error)) => return self.error(location, (error, columns(&token))),
+						_ => return self.error(location, (Error::ParseNoStringError(token.1.to_owned()), columns(&token)))
+					}
+				}
+				if directive == ".asciz" {
+					self.data.push(0);
+				}
+			},
+			".byte" => for operand in operands {
+				match parse_expression(operand, end) {
+					Ok(expression) => {
+						self.reference(&expression, location);
+						self.fixups.push(Fixup { index: self.data.len(), count: 1, expression, location: location.clone() });
+						self.data.push(0);
+					},
+					Err(error) => return self.error(location, error)
+				}
+			},
+			_ => {
+				if operands.len() > 2 {
+					return self.error(location, (Error::ParseOperandCountError(directive, 2, operands.len()), (tokens[0].0, end)))
+				}
+				let bits = self.bits();
+				let count = match parse_expression(operands[0], end) {
+					Ok(expression) => match self.symbols().evaluate(&expression) {
+						Ok(Some(count)) if (0..1 << bits).contains(&count) => count as usize,
+						Ok(Some(count)) => return self.error(location, (Error::ParseValueRangeError(count, bits), expression.columns())),
+						Ok(None) => return self.error(location, (Error::ParseUnresolvedError("the `.fill` count".to_owned()), expression.columns())),
+						Err(error) => return self.error(location, error)
+					},
+					Err(error) => return self.error(location, error)
+				};
+				if let Some(value) = operands.get(1) {
+					match parse_expression(value, end) {
+						Ok(expression) => {
+							self.reference(&expression, location);
+							self.fixups.push(Fixup { index: self.data.len(), count, expression, location: location.clone() })
+						},
+						Err(error) => return self.error(location, error)
+					}
+				}
+				self.data.resize(self.data.len() + count, 0);
+			}
+		}
+		let max = (1 << self.bits()) - 1;
+		if start <= max && self.data.len() > max {
+			self.error(location, (Error::ParseDataRangeError(self.data.len(), max), (tokens[0].0, end)));
+		}
+	}
+
+	fn bank(&mut self, tokens: &[Token], location: &Location, end: usize) {
+		let bank_size = self.bank_size();
+		let bank = match parse_expression(&tokens[1..], end) {
+			Ok(expression) => match self.symbols().evaluate(&expression) {
+				Ok(Some(bank)) if bank >= 0 && bank as usize * bank_size <= u16::MAX as usize => bank as usize,
+				Ok(Some(bank)) => return self.error(location, (Error::ParseBankRangeError(bank), expression.columns())),
+				Ok(None) => return self.error(location, (Error::ParseUnresolvedError("the `.bank` number".to_owned()), expression.columns())),
+				Err(error) => return self.error(location, error)
+			},
+			Err(error) => return self.error(location, error)
+		};
+		if self.words > bank * bank_size {
+			return self.error(location, (Error::ParseBankError(bank, self.words), (tokens[0].0, end)))
+		}
+		while self.words < bank * bank_size {
+			self.push(InstructionBuilder { opcode_hex: Some(INT), columns: (tokens[0].0, end), location: location.clone(), ..Default::default() });
+		}
+	}
+
+	fn wide(&mut self, tokens: &[Token], location: &Location) {
+		if let Some(extra) = tokens.get(1) {
+			return self.error(location, (Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra)))
+		}
+		if !self.builders.is_empty() || !self.data.is_empty() {
+			return self.error(location, (Error::ParseWideError, columns(&tokens[0])))
+		}
+		self.wide = true;
+	}
+
+	fn statement(&mut self, tokens: &[Token], location: &Location, depth: usize) {
+		let rest = match split_label(tokens) {
+			Ok((Some(label), rest)) => {
+				if self.is_label(label.1) {
+					self.error(location, (Error::ParseDuplicateLabelError(label.1.to_owned()), columns(&label)));
+				} else if self.is_defined(label.1) {
+					self.error(location, (Error::ParseDuplicateConstantError(label.1.to_owned()), columns(&label)));
+				} else {
+					self.definitions.insert(label.1.to_owned(), location.span(columns(&label)));
+					self.pending_labels.push(label.1.to_owned());
+				}
+				rest
+			},
+			Ok((None, rest)) => rest,
+			Err(error) => return self.error(location, error)
+		};
+		if rest.is_empty() { return }
+		let end = rest.last().map(|token| token.0 + token.1.len()).unwrap_or(0);
+		match split_constant(rest, end) {
+			Ok(Some((name, expression))) => {
+				if self.is_defined(name.1) {
+					return self.error(location, (Error::ParseDuplicateConstantError(name.1.to_owned()), columns(&name)))
+				}
+				match parse_expression(expression, end) {
+					Ok(expression) => {
+						self.reference(&expression, location);
+						self.definitions.insert(name.1.to_owned(), location.span(columns(&name)));
+						self.constants.insert(name.1.to_owned(), Constant { expression, location: location.clone() });
+						self.constant_names.push(name.1.to_owned());
+					},
+					Err(error) => self.error(location, error)
+				}
+				return
+			},
+			Ok(None) => {},
+			Err(error) => return self.error(location, error)
+		}
+		if DATA.contains(&rest[0].1.to_ascii_lowercase().as_str()) {
+			return self.data(rest, location, end)
+		}
+		if rest[0].1.eq_ignore_ascii_case(".bank") {
+			return self.bank(rest, location, end)
+		}
+		if rest[0].1.eq_ignore_ascii_case(".wide") {
+			return self.wide(rest, location)
+		}
+		if let Some(definition) = self.macros.get(&rest[0].1.to_ascii_lowercase()).cloned() {
+			return self.expand(&definition, rest, location, depth)
+		}
+		let lines = match pseudo(rest, end, self.wide) {
+			Ok(Some(lines)) => lines,
+			Ok(None) => vec![rest.to_vec()],
+			Err(error) => return self.error(location, error)
+		};
+		let mut builders: Vec<InstructionBuilder> = Vec::new();
+		for line in lines {
+			match parse_line(&line, end) {
+				Ok(mut instruction_builder) => {
+					if instruction_builder.extended && !self.wide {
+						return self.error(location, (Error::ParseWideOnlyError(line[0].1.to_owned()), columns(&line[0])))
+					}
+					if self.wide && instruction_builder.opcode_hex == Some(SET) {
+						match instruction_builder.value_expression {
+							Some(Expression::Literal(value, _)) if (0..=Rsize::MAX as i64).contains(&value) => {},
+							Some(_) => instruction_builder.extended = true,
+							None => {}
+						}
+					}
+					instruction_builder.location = location.clone();
+					instruction_builder.columns = (rest[0].0, end);
+					if let Some(ref expression) = instruction_builder.value_expression {
+						self.reference(expression, location);
+					}
+					builders.push(instruction_builder)
+				},
+				Err(error) => return self.error(location, error)
+			};
+		}
+		let mut consumer = builders.iter().map(InstructionBuilder::length).sum::<usize>() - builders.last().map(InstructionBuilder::length).unwrap_or(0);
+		self.bind_labels(false);
+		for mut instruction_builder in builders {
+			instruction_builder.consumer = consumer;
+			consumer = consumer.saturating_sub(instruction_builder.length());
+			self.push(instruction_builder)
+		}
+	}
 
-	let file = File::open(path).unwrap();
-       	for (linenumber, line) in BufReader::new(file).lines().enumerate() {
-		let line = line.unwrap();
-		let line = line.trim();
-		if line.starts_with('#') || line.is_empty() { continue };
-		match assemble_line(line) {
-			Ok(instruction) => { bytecode.push(instruction); debug!("\t{}:\t0x{:4x}\t#{}", linenumber, instruction, line) },
-			Err(error) => {
-				match error {
-					Error::ParseNoOpcodeError => println!("Error at line {}: {}\n\t-> Hint: Invalid opcode", linenumber, line),
-					Error::ParseNoTargetError => println!("Error at line {}: {}\n\t-> Hint: Must be a register", linenumber, line),
-					Error::ParseNoValueError => println!("Error at line {}: {}\n\t-> Hint: Must be a register (8bit integer in case of \"set\")", linenumber, line),
+	fn expand(&mut self, definition: &Macro, tokens: &[Token], location: &Location, depth: usize) {
+		let name = tokens[0];
+		if depth >= MACRO_DEPTH {
+			self.macro_overflow = true;
+			return self.error(location, (Error::ParseMacroDepthError(name.1.to_owned(), MACRO_DEPTH), columns(&name)))
+		}
+		let arguments = match split_arguments(&tokens[1..]) {
+			Ok(arguments) => arguments,
+			Err(error) => return self.error(location, error)
+		};
+		if arguments.len() != definition.parameters.len() {
+			let end = tokens.last().map(|token| token.0 + token.1.len()).unwrap_or(0);
+			return self.error(location, (Error::ParseMacroArgumentsError(name.1.to_owned(), definition.parameters.len(), arguments.len()), (name.0, end)))
+		}
+		self.expansions += 1;
+		let expansion = self.expansions;
+		let note = Note { message: format!("in expansion of macro `{}`", name.1), span: location.span(columns(&name)), source: location.sloc.clone() };
+		for line in &definition.body {
+			let mut expanded = line.clone();
+			expanded.expansion = Some(note.clone()).into_iter().chain(location.expansion.iter().cloned()).collect();
+			expanded.invocation = Some(location.invocation.clone().unwrap_or_else(|| note.span.clone()));
+			let tokens = match tokenize(&line.sloc) {
+				Ok(tokens) => tokens,
+				Err(error) => { self.error(&expanded, error); continue }
+			};
+			let mut substituted: Vec<(usize, String)> = Vec::new();
+			for token in tokens {
+				let (name, suffix) = match token.1.strip_suffix(':') {
+					Some(name) => (name, ":"),
+					None => (token.1, "")
 				};
-				return Err(error)
+				if let Some(index) = definition.parameters.iter().position(|parameter| parameter == token.1) {
+					substituted.extend(arguments[index].iter().map(|argument| (token.0, argument.1.to_owned())));
+				} else if definition.locals.iter().any(|local| local == name) {
+					substituted.push((token.0, format!("{}@{}{}", name, expansion, suffix)));
+				} else {
+					substituted.push((token.0, token.1.to_owned()));
+				}
+			}
+			let tokens: Vec<Token> = substituted.iter().map(|&(column, ref token)| (column, token.as_str())).collect();
+			self.statement(&tokens, &expanded, depth + 1);
+			if self.macro_overflow {
+				break
+			}
+		}
+		if depth == 0 {
+			self.macro_overflow = false;
+		}
+	}
+
+	fn define(&mut self, tokens: &[Token], location: &Location) -> Macro {
+		let end = tokens.last().map(|token| token.0 + token.1.len()).unwrap_or(0);
+		let mut definition = Macro { name: String::new(), columns: (0, 0), location: location.clone(), parameters: Vec::new(), locals: Vec::new(), body: Vec::new() };
+		match parse_macro_header(tokens, end) {
+			Ok((name, parameters)) => {
+				definition.name = name.1.to_owned();
+				definition.columns = columns(&name);
+				definition.parameters = parameters;
+			},
+			Err(error) => self.error(location, error)
+		}
+		definition
+	}
+
+	fn finish(&mut self, mut definition: Macro) {
+		if definition.name.is_empty() {
+			return
+		}
+		for line in &definition.body {
+			if let Ok(tokens) = tokenize(&line.sloc) {
+				if let Some(label) = tokens.first().and_then(|token| token.1.strip_suffix(':')) {
+					definition.locals.push(label.to_owned());
+				}
+			}
+		}
+		let key = definition.name.to_ascii_lowercase();
+		if self.macros.contains_key(&key) {
+			return self.error(&definition.location, (Error::ParseDuplicateMacroError(definition.name.clone()), definition.columns))
+		}
+		self.macros.insert(key, Rc::new(definition));
+	}
+
+	fn read<R: BufRead>(&mut self, mut reader: R, path: &str, canonical: PathBuf, included_from: &[Note]) {
+		let mut definition: Option<Macro> = None;
+		self.files.push(path.to_owned());
+		self.includes.push((canonical, path.to_owned()));
+
+		let mut linenumber = 0;
+		loop {
+			linenumber += 1;
+			let mut location = Location { file: path.to_owned(), linenumber, sloc: String::new(), expansion: included_from.to_vec(), invocation: None };
+			location.sloc = match read_line(&mut reader) {
+				Ok(Some(Ok(line))) => line,
+				Ok(Some(Err((line, column)))) => {
+					location.sloc = line;
+					self.error(&location, (Error::EncodingError, (column, column + 1)));
+					continue
+				},
+				Ok(None) => break,
+				Err(error) => {
+					self.error(&location, (Error::IoError(error), (0, 0)));
+					break
+				}
+			};
+			let tokens = match tokenize(&location.sloc) {
+				Ok(tokens) => tokens,
+				Err(error) => { self.error(&location, error); continue }
+			};
+			if let Some(reserved) = tokens.iter().find(|token| token.1.contains('@') && !token.1.starts_with(['\'', '"'])) {
+				self.error(&location, (Error::ParseReservedNameError(reserved.1.to_owned()), columns(reserved)));
+				continue
+			}
+			let directive = tokens.first().map(|token| token.1.to_ascii_lowercase());
+			match (definition.take(), directive.as_deref()) {
+				(Some(open), Some(".endm")) => {
+					if let Some(extra) = tokens.get(1) {
+						self.error(&location, (Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra)));
+					}
+					self.finish(open)
+				},
+				(Some(open), Some(".macro")) => {
+					self.error(&location, (Error::ParseNestedMacroError(open.name.clone()), columns(&tokens[0])));
+					definition = Some(open)
+				},
+				(Some(mut open), _) => {
+					open.body.push(location.clone());
+					definition = Some(open)
+				},
+				(None, Some(".macro")) => definition = Some(self.define(&tokens, &location)),
+				(None, Some(".endm")) => self.error(&location, (Error::ParseUnexpectedTokenError(tokens[0].1.to_owned()), columns(&tokens[0]))),
+				(None, Some(".include")) => self.include(&tokens, &location),
+				(None, _) => self.statement(&tokens, &location, 0)
 			}
 		};
-       	};
 
-	Ok(bytecode)
+		if let Some(open) = definition.filter(|open| !open.name.is_empty()) {
+			self.error(&open.location, (Error::ParseUnterminatedMacroError(open.name.clone()), open.columns));
+		}
+		self.includes.pop();
+	}
+
+	fn include(&mut self, tokens: &[Token], location: &Location) {
+		let token = match tokens {
+			[_, token] => *token,
+			[directive] => return self.error(location, (Error::ParseInvalidIncludeError(String::new()), columns(directive))),
+			[_, _, extra, ..] => return self.error(location, (Error::ParseUnexpectedTokenError(extra.1.to_owned()), columns(extra))),
+			[] => return
+		};
+		let name = match token.1.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+			Some(name) if !name.is_empty() => name,
+			_ => return self.error(location, (Error::ParseInvalidIncludeError(token.1.to_owned()), columns(&token)))
+		};
+		let path = Path::new(&location.file).parent().unwrap_or_else(|| Path::new("")).join(name);
+		let display = path.to_string_lossy().into_owned();
+		let canonical = match fs::canonicalize(&path) {
+			Ok(canonical) => canonical,
+			Err(error) => return self.error(location, (Error::ParseIncludeError(display, error), columns(&token)))
+		};
+		if let Some(position) = self.includes.iter().position(|(included, _)| *included == canonical) {
+			let chain: Vec<&str> = self.includes[position..].iter().map(|(_, file)| file.as_str()).chain(Some(display.as_str())).collect();
+			return self.error(location, (Error::ParseIncludeCycleError(chain.join(" -> ")), columns(&token)))
+		}
+		if !self.included.insert(canonical.clone()) {
+			return
+		}
+		match File::open(&path) {
+			Ok(file) => {
+				let note = Note { message: "included from here".to_owned(), span: location.span(columns(&token)), source: location.sloc.clone() };
+				let included_from: Vec<Note> = Some(note).into_iter().chain(location.expansion.iter().cloned()).collect();
+				self.read(BufReader::new(file), &display, canonical, &included_from)
+			},
+			Err(error) => self.error(location, (Error::ParseIncludeError(display, error), columns(&token)))
+		}
+	}
+
+	fn assemble(mut self) -> Result<Assembly, Vec<Diagnostic>> {
+		self.bind_labels(false);
+		let bits = self.bits();
+		let bank_size = self.bank_size();
+		let mut bytecode: Bytecode = Vec::new();
+		let mut origins: Vec<Origin> = Vec::new();
+		let mut data = self.data.clone();
+		let mut symbols = Symbols { labels: &self.labels, data_labels: &self.data_labels, constants: &self.constants, values: HashMap::new(), pending: Vec::new(), diagnostics: Vec::new() };
+		for name in &self.constant_names {
+			let _ = symbols.constant(name, (0, 0));
+		}
+
+		for fixup in &self.fixups {
+			match symbols.immediate(&fixup.expression, bits, bank_size, 0) {
+				Ok(Some(value)) => for slot in &mut data[fixup.index..fixup.index + fixup.count] { *slot = value },
+				Ok(None) => {},
+				Err((error, columns)) => self.diagnostics.push(fixup.location.diagnostic(error, columns))
+			}
+		}
+
+		let mut address = 0;
+		for mut instruction_builder in self.builders {
+			let index = address;
+			address += instruction_builder.length();
+			let location = instruction_builder.location.clone();
+			let value_columns = instruction_builder.value_columns;
+			let statement_columns = instruction_builder.columns;
+			if let Some(expression) = instruction_builder.value_expression.take() {
+				let bits = if instruction_builder.extended { bits } else { 8 };
+				match symbols.immediate(&expression, bits, bank_size, index + instruction_builder.consumer) {
+					Ok(Some(value)) if instruction_builder.extended => instruction_builder.immediate = Some(value),
+					Ok(Some(value)) => instruction_builder.value_hex = Some(value as Rsize),
+					Ok(None) => continue,
+					Err((error, columns)) => { self.diagnostics.push(location.diagnostic(error, columns)); continue }
+				}
+			}
+			match instruction_builder.build_words() {
+				Ok(words) => {
+					debug!("\t{}:\t0x{:04x}\t{}\t#{}", location.linenumber, words[0], disassemble_at(&words, 0, self.wide).unwrap_or_default(), location.sloc.trim());
+					for word in words {
+						bytecode.push(word);
+						origins.push(Origin { span: location.span(statement_columns), source: location.sloc.clone() });
+					}
+				},
+				Err(error) => self.diagnostics.push(location.diagnostic(error, value_columns))
+			};
+		}
+
+		let constants = symbols.values.iter().filter_map(|(name, value)| value.map(|value| (name.to_owned(), value))).collect();
+		self.diagnostics.append(&mut symbols.diagnostics);
+		if !self.diagnostics.is_empty() {
+			let files = &self.files;
+			self.diagnostics.sort_by_key(|diagnostic| (files.iter().position(|file| *file == diagnostic.span.file), diagnostic.span.line));
+			return Err(self.diagnostics)
+		}
+		Ok(Assembly { bytecode, labels: self.labels, constants, data, data_labels: self.data_labels, origins, definitions: self.definitions, references: self.references, wide: self.wide })
+	}
+}
+
+pub fn assemble_reader<R: BufRead>(reader: R, path: &str) -> Result<Assembly, Vec<Diagnostic>> {
+	let mut assembler = Assembler::default();
+	let canonical = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+	assembler.included.insert(canonical.clone());
+	assembler.read(reader, path, canonical, &[]);
+	assembler.assemble()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn errors(source: &str) -> Vec<Diagnostic> {
+		match assemble_str(source, "test.rvm") {
+			Ok(assembly) => panic!("assembled {:?}", assembly.bytecode),
+			Err(diagnostics) => diagnostics
+		}
+	}
+
+	fn bytecode(source: &str) -> Bytecode {
+		match assemble_str(source, "test.rvm") {
+			Ok(assembly) => assembly.bytecode,
+			Err(diagnostics) => panic!("{}", diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect::<Vec<_>>().join("\n"))
+		}
+	}
+
+	#[test]
+	fn diagnostics_point_at_the_offending_token() {
+		let diagnostics = errors("\tset r0 1\n\tadd r0 r9\n\tfoo r1 r2\n");
+		assert_eq!(diagnostics.len(), 2);
+		assert_eq!(diagnostics[0].span, Span { file: "test.rvm".to_owned(), line: 2, columns: (8, 10) });
+		assert_eq!(diagnostics[0].error.to_string(), "register `r9` does not exist, registers are r0-r7, rn, rd, rf, rc, rs, rb");
+		assert_eq!(diagnostics[1].span.line, 3);
+		assert_eq!(diagnostics[1].span.columns, (1, 4));
+		assert!(diagnostics[1].error.to_string().starts_with("unknown instruction `foo`"));
+	}
+
+	#[test]
+	fn diagnostics_render_an_excerpt() {
+		let diagnostics = errors("set r0\n");
+		assert_eq!(diagnostics[0].to_string(), "error: missing value, expected a register (or an expression for `set`)\n --> test.rvm:1:7\n  |\n1 | set r0\n  |       ^");
+	}
+
+	#[test]
+	fn all_errors_are_collected_in_source_order() {
+		let diagnostics = errors("jmp nowhere\nset r0 256\nx: set r0 1\nx: set r0 2\n");
+		let lines: Vec<usize> = diagnostics.iter().map(|diagnostic| diagnostic.span.line).collect();
+		assert_eq!(lines, vec![1, 2, 4]);
+		assert!(matches!(diagnostics[0].error, Error::ParseUndefinedLabelError(ref label) if label == "nowhere"));
+		assert!(matches!(diagnostics[1].error, Error::ParseValueRangeError(256, 8)));
+		assert!(matches!(diagnostics[2].error, Error::ParseDuplicateLabelError(ref label) if label == "x"));
+	}
+
+	#[test]
+	fn comments_and_blank_lines_are_skipped() {
+		assert_eq!(bytecode("# comment\n\n\tset r0, 1 ; trailing\n"), vec![0x1001]);
+	}
+
+	#[test]
+	fn literals_in_every_radix_and_characters() {
+		assert_eq!(bytecode("\tset r0 0b101\n\tset r1 0o17\n\tset r2 0x1F\n\tset r3 'A'\n\tset r4 '\\n'\n\tset r5 '\\x41'\n\tset r6 '\\''\n"), vec![0x1005, 0x110f, 0x121f, 0x1341, 0x140a, 0x1541, 0x1627]);
+		assert!(matches!(errors("\tset r0 0b102\n")[0].error, Error::ParseInvalidLiteralError(ref found) if found == "0b102"));
+		assert!(matches!(errors("\tset r0 '\\q'\n")[0].error, Error::ParseInvalidLiteralError(_)));
+		assert!(matches!(errors("\tset r0 'ab'\n")[0].error, Error::ParseInvalidLiteralError(_)));
+	}
+
+	#[test]
+	fn immediates_are_range_checked_at_the_boundaries() {
+		assert_eq!(bytecode("\tset r0 -128\n\tset r1 -1\n\tset r2 255\n\tset r3 0\n"), vec![0x1080, 0x11ff, 0x12ff, 0x1300]);
+		let diagnostics = errors("\tset r0 -129\n\tset r1 256\n\tset r2 300\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseValueRangeError(-129, 8)));
+		assert!(matches!(diagnostics[1].error, Error::ParseValueRangeError(256, 8)));
+		assert_eq!(diagnostics[2].error.to_string(), "value 300 does not fit in 8 bits");
+		assert_eq!(diagnostics[2].span.columns, (8, 11));
+	}
+
+	#[test]
+	fn whitespace_and_commas_are_interchangeable() {
+		let expected = vec![0x4001, 0x4001, 0x4001, 0x4001];
+		assert_eq!(bytecode("\tadd r0 r1\n  add   r0    r1\n\t\tadd\tr0,\tr1\nadd r0,r1\n"), expected);
+	}
+
+	#[test]
+	fn trailing_comments_end_the_line() {
+		assert_eq!(bytecode("\tset r0 1 # one\n\tset r1 2; two\n\tset r2 3 ;# three\n; whole line\n# another\n"), vec![0x1001, 0x1102, 0x1203]);
+	}
+
+	#[test]
+	fn mnemonics_registers_and_prefixes_ignore_case() {
+		assert_eq!(bytecode("\tSET R0, 0X41\n\tAdd Rn, RD\n\tJMP 0B11\n"), vec![0x1041, 0x4809, 0x1803]);
+	}
+
+	#[test]
+	fn extra_operands_are_rejected() {
+		let diagnostics = errors("\tadd r0 r1 r2\n\tset r0 1 2\n\tint r0\n");
+		let found: Vec<&str> = diagnostics.iter().map(|diagnostic| match diagnostic.error { Error::ParseUnexpectedTokenError(ref found) => found.as_str(), _ => "" }).collect();
+		assert_eq!(found, vec!["r2", "2", "r0"]);
+		assert_eq!(diagnostics[0].span.columns, (11, 13));
+	}
+
+	#[test]
+	fn constants_evaluate_in_any_order() {
+		let assembly = assemble_str("SIZE = BASE * 2\n.equ BASE, 3\n\tset r0 SIZE + 1\n\tset r1 -1\n\tset r2 'A'\n", "test.rvm").unwrap();
+		assert_eq!(assembly.bytecode, vec![0x1007, 0x11ff, 0x1241]);
+		assert_eq!(assembly.constants["SIZE"], 6);
+		assert_eq!(assembly.constants["BASE"], 3);
+	}
+
+	#[test]
+	fn fill_counts_must_be_known_when_they_are_used() {
+		let diagnostics = errors("N = end - start\nstart:\tnop\nbuf: .fill N, 7\nend:\thlt\n");
+		assert_eq!(diagnostics.len(), 1);
+		assert!(matches!(diagnostics[0].error, Error::ParseUnresolvedError(_)));
+		assert_eq!(diagnostics[0].error.to_string(), "the `.fill` count must be resolvable at this point, it cannot depend on labels defined later");
+		assert_eq!((diagnostics[0].span.line, diagnostics[0].span.columns), (3, (11, 12)));
+		assert_eq!(assemble_str("N = 3\nbuf: .fill N, 7\n\thlt\n", "test.rvm").unwrap().data, vec![7, 7, 7]);
+	}
+
+	#[test]
+	fn constant_errors_are_reported_at_the_definition() {
+		let diagnostics = errors("A = B + 1\nB = A\n\tset r0 A\n");
+		assert!(diagnostics.iter().any(|diagnostic| matches!(diagnostic.error, Error::ParseRecursiveConstantError(_)) && diagnostic.span.line <= 2));
+		let diagnostics = errors("X = 1\nX = 2\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseDuplicateConstantError(ref name) if name == "X"));
+		assert_eq!(diagnostics[0].span.line, 2);
+		let diagnostics = errors("\tset r0 300 - 1\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseValueRangeError(299, 8)));
+		assert_eq!(diagnostics[0].span.columns, (8, 15));
+	}
+
+	#[test]
+	fn macros_expand_with_arguments_and_local_labels() {
+		let source = ".macro skip2 a, b\n\tchk a b\nover:\n\tset r7 over\n.endm\n\tskip2 r0, r1\n\tskip2 r2 r3\n";
+		let assembly = assemble_str(source, "test.rvm").unwrap();
+		assert_eq!(assembly.bytecode, vec![0x8001, 0x1701, 0x8203, 0x1703]);
+		assert_eq!(assembly.labels["over@1"], 1);
+		assert_eq!(assembly.labels["over@2"], 3);
+	}
+
+	#[test]
+	fn macro_names_are_case_insensitive() {
+		let source = ".macro Push2 a, b\n\tpsh a a\n\tpsh b b\n.endm\n\tPUSH2 r0, r1\n\tpush2 r2, r3\n";
+		assert_eq!(bytecode(source), vec![0x2000, 0x2101, 0x2202, 0x2303]);
+		let diagnostics = errors(".macro twice\n.endm\n.macro TWICE\n.endm\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseDuplicateMacroError(ref name) if name == "TWICE"));
+	}
+
+	#[test]
+	fn macro_errors_note_the_invocation() {
+		let diagnostics = errors(".macro bad a\n\tadd a r9\n.endm\n\tbad r0\n");
+		assert_eq!(diagnostics.len(), 1);
+		assert_eq!(diagnostics[0].span.line, 2);
+		assert_eq!(diagnostics[0].notes[0].message, "in expansion of macro `bad`");
+		assert_eq!(diagnostics[0].notes[0].span.line, 4);
+		assert!(matches!(errors(".macro one a\n.endm\n\tone\n")[0].error, Error::ParseMacroArgumentsError(_, 1, 0)));
+		assert!(matches!(errors(".macro add\n.endm\n")[0].error, Error::ParseInvalidMacroError(_)));
+		assert!(matches!(errors(".macro loop\n\tloop\n.endm\n\tloop\n")[0].error, Error::ParseMacroDepthError(_, 16)));
+	}
+
+	#[test]
+	fn runaway_macros_report_one_error_per_invocation() {
+		let diagnostics = errors(".macro twice\n\ttwice\n\ttwice\n.endm\n\ttwice\n\tnop\n\ttwice\n");
+		assert_eq!(diagnostics.len(), 2);
+		assert!(diagnostics.iter().all(|diagnostic| matches!(diagnostic.error, Error::ParseMacroDepthError(_, 16))));
+		assert_eq!(diagnostics[1].notes.last().unwrap().span.line, 7);
+	}
+
+	#[test]
+	fn at_signs_are_reserved_for_generated_labels() {
+		let diagnostics = errors(".macro spin\nl:\tjmp l\n.endm\nl@1:\tspin\n\tjmp l@1\n.macro bad\n\tset r0 x@2\n.endm\n");
+		let found: Vec<&str> = diagnostics.iter().map(|diagnostic| match diagnostic.error { Error::ParseReservedNameError(ref name) => name.as_str(), _ => "" }).collect();
+		assert_eq!(found, vec!["l@1:", "l@1", "x@2"]);
+		assert_eq!(diagnostics[0].span.columns, (0, 4));
+		assert_eq!(bytecode("\tset r0 '@'\n.ascii \"a@b\"\n"), vec![0x1040]);
+	}
+
+	#[test]
+	fn pseudo_instructions_expand() {
+		assert_eq!(bytecode("\tnop\n\thlt\n\tmov r1 r2\n\tmov r3 r3\n\tmov rn 4\n\tpush r5\n\tpop r6\n"), vec![0xe000, 0xfc0c, 0x0000, 0xf101, 0xe102, 0xe303, 0x1804, 0x2505, 0x3606]);
+		assert_eq!(bytecode("loop:\tjeq r0 r1 loop\n"), vec![0x8001, 0x1b01, 0x1700, 0x9807]);
+	}
+
+	#[test]
+	fn mov_rejects_special_register_targets() {
+		for register in &["rn", "rd", "rf", "rc", "rs", "rb"] {
+			let diagnostics = errors(&format!("\tmov {} r1\n", register));
+			assert!(matches!(diagnostics[0].error, Error::ParseMovTargetError(ref target) if target == register));
+			assert_eq!(diagnostics[0].span.columns, (5, 7));
+		}
+		assert_eq!(bytecode("\tmov r0 rn\n\tmov rd rd\n\tmov rs 1\n"), vec![0xf000, 0xe008, 0xe909, 0x1c01]);
+	}
+
+	fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+		let directory = std::env::temp_dir().join(format!("rvm-{}-{}", name, std::process::id()));
+		for &(file, contents) in files {
+			let path = directory.join(file);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, contents).unwrap();
+		}
+		directory
+	}
+
+	#[test]
+	fn includes_are_read_once_relative_to_the_including_file() {
+		let directory = scratch("include", &[
+			("main.rvm", ".include \"lib/defs.rvm\"\n.include \"lib/defs.rvm\"\n\tset r0 ANSWER\n\thelper\n"),
+			("lib/defs.rvm", ".include \"macros.rvm\"\nANSWER = 42\n"),
+			("lib/macros.rvm", ".macro helper\n\tset r1 1\n.endm\n")
+		]);
+		let assembly = assemble_file(directory.join("main.rvm").to_str().unwrap()).unwrap();
+		assert_eq!(assembly.bytecode, vec![0x102a, 0x1101]);
+		assert!(assembly.definitions["ANSWER"].file.ends_with("defs.rvm"));
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn include_errors_point_into_the_included_file() {
+		let directory = scratch("include-errors", &[
+			("main.rvm", ".include \"a.rvm\"\n.include \"missing.rvm\"\n"),
+			("a.rvm", "\tset r0 1\n.include \"b.rvm\"\n"),
+			("b.rvm", "\tadd r0 r9\n.include \"a.rvm\"\n")
+		]);
+		let diagnostics = assemble_file(directory.join("main.rvm").to_str().unwrap()).unwrap_err();
+		assert_eq!(diagnostics.len(), 3);
+		assert!(diagnostics[0].span.file.ends_with("main.rvm") && matches!(diagnostics[0].error, Error::ParseIncludeError(..)));
+		assert!(diagnostics[1].span.file.ends_with("b.rvm") && diagnostics[1].span.line == 1);
+		assert_eq!(diagnostics[1].notes.iter().map(|note| note.message.as_str()).collect::<Vec<_>>(), vec!["included from here"; 2]);
+		assert!(matches!(diagnostics[2].error, Error::ParseIncludeCycleError(_)));
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn bare_labels_from_other_banks_are_rejected() {
+		let source = "\tset r3 far\n\tcns rn r3\n\tjeq r0 r1 far\n\tjmp far\n.byte far\n\tljmp far\n\tset rb (far) >> 8\n\tset r3 (far) & 0xff\n.bank 1\nfar:\tset r0 far\n\thlt\n";
+		let diagnostics = errors(source);
+		let lines: Vec<usize> = diagnostics.iter().map(|diagnostic| diagnostic.span.line).collect();
+		assert_eq!(lines, vec![1, 3, 4, 5]);
+		assert!(diagnostics.iter().all(|diagnostic| matches!(diagnostic.error, Error::ParseFarLabelError(ref label, 1, 0) if label == "far")));
+		assert_eq!(diagnostics[0].span.columns, (8, 11));
+	}
+
+	#[test]
+	fn bank_numbers_must_be_known_when_they_are_used() {
+		let diagnostics = errors("B = end - start\nstart:\tnop\n.bank B\nend:\thlt\n");
+		assert_eq!(diagnostics.len(), 1);
+		assert!(matches!(diagnostics[0].error, Error::ParseUnresolvedError(ref operand) if operand == "the `.bank` number"));
+		assert_eq!((diagnostics[0].span.line, diagnostics[0].span.columns), (3, (6, 7)));
+	}
+
+	#[test]
+	fn conditional_jumps_check_the_bank_of_their_cns() {
+		let straddling = format!("\tjmp go\ndone:\tset r2 9\n\thlt\ngo:\n{}\tjeq r0 r0 done\n\tset r2 1\n\thlt\n", "\tnop\n".repeat(249));
+		let diagnostics = errors(&straddling);
+		assert_eq!(diagnostics.len(), 1);
+		assert!(matches!(diagnostics[0].error, Error::ParseFarLabelError(ref label, 0, 1) if label == "done"));
+		assert_eq!(diagnostics[0].span.line, 254);
+		let near = format!("\tjmp go\ndone:\tset r2 9\n\thlt\ngo:\n{}\tjeq r0 r0 done\n\tset r2 1\n\thlt\n", "\tnop\n".repeat(248));
+		assert_eq!(bytecode(&near)[0xfe], 0x1701);
+	}
+
+	#[test]
+	fn labels_in_expressions_are_full_indices() {
+		let diagnostics = errors("\tset r7 loop + 0\n\tset r7 loop\n.bank 1\nloop:\tset r6 (loop) & 0xff\n\tset r5 loop + 0\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseValueRangeError(256, 8)));
+		assert!(matches!(diagnostics[1].error, Error::ParseFarLabelError(_, 1, 0)));
+		assert!(diagnostics[1].error.to_string().contains("a bare label must be in the bank of the instruction using it and expressions see its full index"));
+		assert!(matches!(diagnostics[2].error, Error::ParseValueRangeError(256, 8)));
+		assert_eq!(diagnostics.len(), 3);
+	}
+
+	#[test]
+	fn explicit_far_addresses_link() {
+		let assembly = assemble_str("\tljmp far\n\tset r3 (far) & 0xff\n.bank 1\nfar:\tset r0 far\n", "test.rvm").unwrap();
+		assert_eq!(&assembly.bytecode[..3], &[0x1d01, 0x1800, 0x1300]);
+		assert_eq!(assembly.bytecode[0x100], 0x1000);
+	}
+
+	#[test]
+	fn wide_sets_pick_the_short_or_extended_form() {
+		assert_eq!(bytecode(".wide\n\tset r0 5\n\tset r1 1000\n\tset.w r2 7\nloop:\tset r3 loop\n"), vec![0x1005, 0x1f01, 0x03e8, 0x1f02, 0x0007, 0x1f03, 0x0005]);
+		assert!(matches!(errors("\tset.w r0 1\n")[0].error, Error::ParseWideOnlyError(ref mnemonic) if mnemonic == "set.w"));
+		assert!(matches!(errors(".wide\n\tset r0 65536\n")[0].error, Error::ParseValueRangeError(65536, 16)));
+	}
+
+	#[test]
+	fn wide_labels_are_range_checked_as_u8_operands() {
+		let source = format!(".wide\n\tadi r0 near\n\tadi r0 target\n\tset r1 target\nnear:\n{}target:\thlt\n", "\tnop\n".repeat(294));
+		let diagnostics = errors(&source);
+		assert_eq!(diagnostics.len(), 1);
+		assert!(matches!(diagnostics[0].error, Error::ParseValueRangeError(300, 8)));
+		assert_eq!((diagnostics[0].span.line, diagnostics[0].span.columns), (3, (8, 14)));
+	}
+
+	#[test]
+	fn secondary_instructions_encode_as_an_escape_and_operands() {
+		assert_eq!(bytecode("\tmod r1 r2\n\tnot r3\n\tspt r4 r5\n\tcal r6\n\tret\n\tadi r0 200\n\tsbi r1 1\n\tmli r2 3\n\tdvi r7 4\n"), vec![
+			0x0f00, 0x0102, 0x0f01, 0x0300, 0x0f02, 0x0405, 0x0f03, 0x0600, 0x0f04, 0x0000,
+			0x0f10, 0x00c8, 0x0f11, 0x0101, 0x0f12, 0x0203, 0x0f13, 0x0704
+		]);
+		let diagnostics = errors("\tadi r0 256\n\tret r1\n\tnot r1 r2\n\tcal 5\n");
+		assert!(matches!(diagnostics[0].error, Error::ParseValueRangeError(256, 8)));
+		assert!(matches!(diagnostics[1].error, Error::ParseOperandCountError(ref name, 0, 1) if name == "ret"));
+		assert!(matches!(diagnostics[2].error, Error::ParseOperandCountError(ref name, 1, 2) if name == "not"));
+		assert!(matches!(diagnostics[3].error, Error::ParseNoTargetError(Some(_))));
+	}
+
+	#[test]
+	fn missing_files_report_an_io_error() {
+		let path = scratch("missing", &[]).join("nothing.rvm");
+		let diagnostics = assemble_file(path.to_str().unwrap()).unwrap_err();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(matches!(diagnostics[0].error, Error::IoError(ref error) if error.kind() == io::ErrorKind::NotFound));
+		assert_eq!(diagnostics[0].span.file, path.to_str().unwrap());
+	}
+
+	#[test]
+	fn files_are_read_with_crlf_line_endings() {
+		let path = scratch("crlf", &[("main.rvm", "\tset r0 1\r\n\tset r1 2\r\n")]).join("main.rvm");
+		assert_eq!(assemble_file(path.to_str().unwrap()).unwrap().bytecode, vec![0x1001, 0x1102]);
+	}
+
+	#[test]
+	fn invalid_utf8_is_reported_with_its_position() {
+		let diagnostics = assemble_reader(&b"\tset r0 1\n\tset r1 \xff2\n\tset r2 \xc3\n"[..], "bytes.rvm").unwrap_err();
+		assert_eq!(diagnostics.len(), 2);
+		assert!(diagnostics.iter().all(|diagnostic| matches!(diagnostic.error, Error::EncodingError)));
+		assert_eq!((diagnostics[0].span.line, diagnostics[0].span.columns), (2, (8, 9)));
+		assert_eq!((diagnostics[1].span.line, diagnostics[1].span.columns), (3, (8, 9)));
+		assert_eq!(diagnostics[0].source, "\tset r1 \u{fffd}2");
+		assert_eq!(diagnostics[0].error.to_string(), "invalid UTF-8 in source file");
+	}
 }
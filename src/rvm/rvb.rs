@@ -0,0 +1,204 @@
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::fs::File;
+
+use super::*;
+
+pub const MAGIC: [u8; 4] = *b"RVMB";
+pub const VERSION: u8 = 0x1;
+pub const WIDE_VERSION: u8 = 0x2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+	pub version: u8,
+	pub entry: u16,
+	pub bytecode: Bytecode,
+	pub stack: Stack,
+	pub debug: Vec<u8>
+}
+
+impl Program {
+	pub fn new(bytecode: Bytecode) -> Program {
+		Program { version: VERSION, entry: 0, bytecode, stack: Stack::new(), debug: Vec::new() }
+	}
+
+	pub fn is_wide(&self) -> bool {
+		self.version == WIDE_VERSION
+	}
+
+	fn cell_size(&self) -> usize {
+		if self.is_wide() { 2 } else { 1 }
+	}
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Error> {
+	reader.read_exact(buffer).map_err(|error| match error.kind() {
+		io::ErrorKind::UnexpectedEof => Error::FormatTruncatedError,
+		_ => Error::IoError(error)
+	})
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, Error> {
+	let mut buffer = [0; 2];
+	read_exact(reader, &mut buffer)?;
+	Ok(u16::from_be_bytes(buffer))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+	let mut buffer = [0; 4];
+	read_exact(reader, &mut buffer)?;
+	Ok(u32::from_be_bytes(buffer))
+}
+
+// magic, version, reserved, entry (u16), code length (u16, words), code, stack length (u16, cells), stack, debug length (u32), debug
+pub fn read_from<R: Read>(reader: &mut R) -> Result<Program, Error> {
+	let mut magic = [0; 4];
+	read_exact(reader, &mut magic)?;
+	if magic != MAGIC {
+		return Err(Error::FormatMagicError)
+	}
+
+	let mut version = [0; 2];
+	read_exact(reader, &mut version)?;
+	if version[0] != VERSION && version[0] != WIDE_VERSION {
+		return Err(Error::FormatVersionError(version[0]))
+	}
+	let wide = version[0] == WIDE_VERSION;
+
+	let entry = read_u16(reader)?;
+	let length = read_u16(reader)?;
+	if entry > length.saturating_sub(1) {
+		return Err(Error::FormatRangeError)
+	}
+	let mut bytecode = Bytecode::with_capacity(length as usize);
+	for _ in 0..length {
+		bytecode.push(read_u16(reader)?);
+	}
+
+	let length = read_u16(reader)?;
+	if !wide && length > Rsize::MAX as u16 {
+		return Err(Error::FormatRangeError)
+	}
+	let mut stack = vec![0; length as usize * if wide { 2 } else { 1 }];
+	read_exact(reader, &mut stack)?;
+
+	let length = read_u32(reader)?;
+	let mut debug = Vec::new();
+	reader.take(length as u64).read_to_end(&mut debug).map_err(Error::IoError)?;
+	if debug.len() != length as usize {
+		return Err(Error::FormatTruncatedError)
+	}
+
+	Ok(Program { version: version[0], entry, bytecode, stack, debug })
+}
+
+pub fn write_to<W: Write>(writer: &mut W, program: &Program) -> Result<(), Error> {
+	let cells = program.stack.len() / program.cell_size();
+	let max = if program.is_wide() { u16::MAX as usize } else { Rsize::MAX as usize };
+	if program.bytecode.len() > u16::MAX as usize || cells > max || !program.stack.len().is_multiple_of(program.cell_size()) || program.debug.len() > u32::MAX as usize {
+		return Err(Error::FormatRangeError)
+	}
+
+	let mut buffer = Vec::new();
+	buffer.extend_from_slice(&MAGIC);
+	buffer.push(program.version);
+	buffer.push(0);
+	buffer.extend_from_slice(&program.entry.to_be_bytes());
+	buffer.extend_from_slice(&(program.bytecode.len() as u16).to_be_bytes());
+	for instruction in &program.bytecode {
+		buffer.extend_from_slice(&instruction.to_be_bytes());
+	}
+	buffer.extend_from_slice(&(cells as u16).to_be_bytes());
+	buffer.extend_from_slice(&program.stack);
+	buffer.extend_from_slice(&(program.debug.len() as u32).to_be_bytes());
+	buffer.extend_from_slice(&program.debug);
+
+	writer.write_all(&buffer).map_err(Error::IoError)
+}
+
+pub fn is_bytecode(path: &str) -> bool {
+	let mut magic = [0; 4];
+	File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == MAGIC
+}
+
+pub fn load(path: &str) -> Result<Program, Error> {
+	let file = File::open(path).map_err(Error::IoError)?;
+	read_from(&mut BufReader::new(file))
+}
+
+pub fn save(path: &str, program: &Program) -> Result<(), Error> {
+	let file = File::create(path).map_err(Error::IoError)?;
+	let mut writer = BufWriter::new(file);
+	write_to(&mut writer, program)?;
+	writer.flush().map_err(Error::IoError)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::vm::Context;
+
+	fn encode(program: &Program) -> Vec<u8> {
+		let mut buffer = Vec::new();
+		write_to(&mut buffer, program).unwrap();
+		buffer
+	}
+
+	#[test]
+	fn entry_in_a_later_bank_round_trips() {
+		let mut program = Program::new(vec![0; 0x180]);
+		program.entry = 0x120;
+		let loaded = read_from(&mut encode(&program).as_slice()).unwrap();
+		assert_eq!(loaded, program);
+
+		let context: Context = Context::from(loaded);
+		assert_eq!(context.pc(), 0x120);
+		assert_eq!(context.registers[RB as usize], 1);
+		assert_eq!(context.registers[RN as usize], 0x20);
+	}
+
+	#[test]
+	fn wide_entry_is_a_plain_offset() {
+		let mut program = Program::new(vec![0; 0x300]);
+		program.version = WIDE_VERSION;
+		program.entry = 0x2ff;
+		let context: Context<u16> = Context::from(read_from(&mut encode(&program).as_slice()).unwrap());
+		assert_eq!(context.registers[RN as usize], 0x2ff);
+		assert_eq!(context.registers[RB as usize], 0);
+	}
+
+	#[test]
+	fn entry_past_the_code_is_rejected() {
+		let mut program = Program::new(vec![0; 4]);
+		program.entry = 4;
+		assert!(matches!(read_from(&mut encode(&program).as_slice()), Err(Error::FormatRangeError)));
+	}
+
+	#[test]
+	fn wrong_magic_is_rejected() {
+		let mut buffer = encode(&Program::new(vec![0x1001]));
+		buffer[..4].copy_from_slice(b"RVMX");
+		let error = read_from(&mut buffer.as_slice()).unwrap_err();
+		assert!(matches!(error, Error::FormatMagicError));
+		assert_eq!(error.to_string(), "not an rvm bytecode file (wrong magic)");
+	}
+
+	#[test]
+	fn unknown_version_is_rejected() {
+		let mut buffer = encode(&Program::new(vec![0x1001]));
+		buffer[4] = 7;
+		let error = read_from(&mut buffer.as_slice()).unwrap_err();
+		assert!(matches!(error, Error::FormatVersionError(7)));
+		assert_eq!(error.to_string(), "unsupported ISA version 7 (supported: 1, 2 for wide mode)");
+	}
+
+	#[test]
+	fn truncated_files_are_rejected() {
+		let buffer = encode(&Program::new(vec![0x1001, 0x1102, 0x2000]));
+		// the header is 10 bytes, the code ends at 16
+		for &length in &[0, 3, 5, 9, 11, 15] {
+			let error = read_from(&mut &buffer[..length]).unwrap_err();
+			assert!(matches!(error, Error::FormatTruncatedError), "{} bytes: {:?}", length, error);
+			assert_eq!(error.to_string(), "file is truncated");
+		}
+	}
+}
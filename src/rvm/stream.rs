@@ -0,0 +1,105 @@
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::fmt;
+
+pub struct Io {
+	pub input: Box<dyn BufRead + Send>,
+	pub output: Box<dyn Write + Send>
+}
+
+impl Io {
+	pub fn new<R: BufRead + Send + 'static, W: Write + Send + 'static>(input: R, output: W) -> Io {
+		Io { input: Box::new(input), output: Box::new(output) }
+	}
+
+	pub fn stdio() -> Io {
+		Io::new(BufReader::new(io::stdin()), io::stdout())
+	}
+
+	pub fn memory(input: &[u8]) -> (Io, Buffer) {
+		let output = Buffer::default();
+		(Io::new(Cursor::new(input.to_vec()), output.clone()), output)
+	}
+}
+
+impl Default for Io {
+	fn default() -> Io {
+		Io::stdio()
+	}
+}
+
+impl fmt::Debug for Io {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("Io")
+	}
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+	fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+		self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+	pub fn contents(&self) -> Vec<u8> {
+		self.lock().clone()
+	}
+
+	pub fn clear(&self) {
+		self.lock().clear()
+	}
+}
+
+impl Write for Buffer {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.lock().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+	use super::*;
+	use super::super::{R0, R2};
+	use super::super::parser::assemble_str;
+	use super::super::vm::{run_context, Context, Vm};
+
+	fn assert_send<T: Send>() {}
+
+	#[test]
+	fn machines_with_injected_io_are_send() {
+		assert_send::<Io>();
+		assert_send::<Buffer>();
+		assert_send::<Context>();
+		assert_send::<Vm<u16>>();
+		assert_send::<super::super::vm::Fault>();
+	}
+
+	#[test]
+	fn memory_io_captures_output_on_another_thread() {
+		let source = "greeting: .asciz \"hello\"\n\tset r0 greeting\n\tset rs PRINTLINE\n\tint\n\thlt\n";
+		let program = assemble_str(source, "print.rvm").unwrap().program();
+		let (io, output) = Io::memory(b"");
+		let context: Context = Context { io, ..Context::from(program) };
+		thread::spawn(move || run_context(context).map(|_| ()).map_err(|fault| fault.to_string())).join().unwrap().unwrap();
+		assert_eq!(output.contents(), b"hello\n");
+		output.clear();
+		assert!(output.contents().is_empty());
+	}
+
+	#[test]
+	fn memory_io_feeds_readline() {
+		let source = "\tset r0 8\n\tset rs READLINE\n\tint\n\tset r1 0\n\tlpt r2 r1\n\thlt\n";
+		let (io, _) = Io::memory(b"abc\n");
+		let context = run_context(Context { io, ..Context::<u8>::from(assemble_str(source, "read.rvm").unwrap().program()) }).unwrap();
+		assert_eq!(context.stack, b"abc");
+		assert_eq!(context.registers[R0 as usize], 3);
+		assert_eq!(context.registers[R2 as usize], b'a');
+	}
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::char;
+use std::fmt;
+use std::io::Write;
+use super::*;
+use super::stream::Io;
+use super::word::Word;
+
+pub trait Syscall<W: Word = Rsize> {
+	fn call(&mut self, registers: &mut Registers<W>, stack: &mut Stack<W>, io: &mut Io) -> Result<(), VMError>;
+}
+
+impl<W: Word, F> Syscall<W> for F where F: FnMut(&mut Registers<W>, &mut Stack<W>, &mut Io) -> Result<(), VMError> {
+	fn call(&mut self, registers: &mut Registers<W>, stack: &mut Stack<W>, io: &mut Io) -> Result<(), VMError> {
+		self(registers, stack, io)
+	}
+}
+
+pub struct Halt;
+
+impl<W: Word> Syscall<W> for Halt {
+	fn call(&mut self, _registers: &mut Registers<W>, _stack: &mut Stack<W>, _io: &mut Io) -> Result<(), VMError> {
+		Err(VMError::VMHaltError)
+	}
+}
+
+pub struct PrintLine;
+
+impl<W: Word> Syscall<W> for PrintLine {
+	fn call(&mut self, registers: &mut Registers<W>, stack: &mut Stack<W>, io: &mut Io) -> Result<(), VMError> {
+		let mut index = registers[R0 as usize].into();
+		let mut string = String::new();
+		while let Some(raw) = stack.get(index).filter(|raw| **raw != W::default()) {
+			if let Some(chr) = char::from_u32((*raw).into() as u32) {
+				string.push(chr);
+			} else {
+				break
+			}
+			index += 1;
+		}
+		writeln!(io.output, "{}", string).map_err(|_| VMError::VMInterruptError)
+	}
+}
+
+pub struct ReadLine;
+
+impl<W: Word> Syscall<W> for ReadLine {
+	fn call(&mut self, registers: &mut Registers<W>, stack: &mut Stack<W>, io: &mut Io) -> Result<(), VMError> {
+		let mut input = String::new();
+		let length = registers[R0 as usize].into();
+		let mut count = 0;
+		if io.input.read_line(&mut input).is_ok() {
+			input.pop();
+			for chr in input.chars() {
+				if count >= length {
+					break
+				}
+				stack.push(W::from(chr as u8));
+				if let Some(new_rd) = registers[RD as usize].checked_add(W::from(1)) {
+					registers[RD as usize] = new_rd;
+				} else {
+					return Err(VMError::VMStackOverflowError)
+				}
+				count += 1;
+			};
+			registers[R0 as usize] = W::from_usize(count).unwrap_or(W::MAX);
+			debug!("READLINE ok, R0 = {}", registers[R0 as usize]);
+			Ok(())
+		} else {
+			Err(VMError::VMInterruptError)
+		}
+	}
+}
+
+pub struct IsaVersion;
+
+impl<W: Word> Syscall<W> for IsaVersion {
+	fn call(&mut self, registers: &mut Registers<W>, _stack: &mut Stack<W>, _io: &mut Io) -> Result<(), VMError> {
+		registers[R0 as usize] = W::from(W::VERSION);
+		registers[R1 as usize] = W::from(EXTENSION_LEVEL);
+		Ok(())
+	}
+}
+
+pub struct Syscalls<W: Word = Rsize> {
+	handlers: HashMap<W, Box<dyn Syscall<W> + Send>>
+}
+
+impl<W: Word> Syscalls<W> {
+	pub fn empty() -> Syscalls<W> {
+		Syscalls { handlers: HashMap::new() }
+	}
+
+	pub fn register<S: Syscall<W> + Send + 'static>(&mut self, number: W, handler: S) -> Option<Box<dyn Syscall<W> + Send>> {
+		self.handlers.insert(number, Box::new(handler))
+	}
+
+	pub fn unregister(&mut self, number: W) -> Option<Box<dyn Syscall<W> + Send>> {
+		self.handlers.remove(&number)
+	}
+
+	pub fn call(&mut self, number: W, registers: &mut Registers<W>, stack: &mut Stack<W>, io: &mut Io) -> Result<(), VMError> {
+		if let Some(handler) = self.handlers.get_mut(&number) {
+			handler.call(registers, stack, io)
+		} else {
+			Err(VMError::VMUnimplementedError(number.into()))
+		}
+	}
+}
+
+impl<W: Word> Default for Syscalls<W> {
+	fn default() -> Syscalls<W> {
+		let mut syscalls = Syscalls::empty();
+		syscalls.register(W::from(HALT), Halt);
+		syscalls.register(W::from(PRINTLINE), PrintLine);
+		syscalls.register(W::from(READLINE), ReadLine);
+		syscalls.register(W::from(ISAVERSION), IsaVersion);
+		syscalls
+	}
+}
+
+impl<W: Word> fmt::Debug for Syscalls<W> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut numbers: Vec<&W> = self.handlers.keys().collect();
+		numbers.sort();
+		f.debug_struct("Syscalls").field("handlers", &numbers).finish()
+	}
+}
@@ -0,0 +1,118 @@
+use std::io::{self, BufWriter, Write};
+use std::fs::File;
+use std::fmt;
+use super::*;
+use super::disasm::disassemble_at;
+use super::vm::Effect;
+use super::word::Word;
+use super::debuginfo::SourceLine;
+
+pub struct Tracer {
+	writer: Box<dyn Write + Send>,
+	steps: usize
+}
+
+fn escape(string: &str) -> String {
+	let mut escaped = String::with_capacity(string.len() + 2);
+	escaped.push('"');
+	for chr in string.chars() {
+		match chr {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\t' => escaped.push_str("\\t"),
+			chr if (chr as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", chr as u32)),
+			chr => escaped.push(chr)
+		}
+	}
+	escaped.push('"');
+	escaped
+}
+
+impl Tracer {
+	pub fn new<W: Write + Send + 'static>(writer: W) -> Tracer {
+		Tracer { writer: Box::new(writer), steps: 0 }
+	}
+
+	pub fn create(path: &str) -> io::Result<Tracer> {
+		Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+	}
+
+	pub fn record<W: Word>(&mut self, pc: usize, next: usize, bytecode: &[Instruction], location: Option<&SourceLine>, effects: &[Effect<W>], error: Option<&VMError>) -> io::Result<()> {
+		self.steps += 1;
+
+		let mut registers: Vec<(Rsize, W, W)> = Vec::new();
+		let mut stack = Vec::new();
+		let mut syscall = None;
+		for effect in effects {
+			match *effect {
+				Effect::Register { register, old, new } => {
+					if let Some(entry) = registers.iter_mut().find(|entry| entry.0 == register) {
+						entry.2 = new;
+						continue
+					}
+					registers.push((register, old, new));
+				},
+				Effect::Push { index, value } => stack.push(format!("{{\"op\":\"push\",\"index\":{},\"value\":{}}}", index, value)),
+				Effect::Pop { index, value } => stack.push(format!("{{\"op\":\"pop\",\"index\":{},\"value\":{}}}", index, value)),
+				Effect::Load { index, value } => stack.push(format!("{{\"op\":\"load\",\"index\":{},\"value\":{}}}", index, value)),
+				Effect::Store { index, old, new } => stack.push(format!("{{\"op\":\"store\",\"index\":{},\"old\":{},\"new\":{}}}", index, old, new)),
+				Effect::Syscall(number) => syscall = Some(number)
+			}
+		}
+		let registers: Vec<String> = registers.iter()
+			.filter(|&&(_, old, new)| old != new)
+			.map(|&(register, old, new)| format!("{{\"register\":\"{}\",\"old\":{},\"new\":{}}}", REGISTERS[register as usize], old, new))
+			.collect();
+
+		let word = bytecode.get(pc).map(|instruction| format!("\"0x{:04x}\"", instruction)).unwrap_or_else(|| "null".to_owned());
+		let disassembly = disassemble_at(bytecode, pc, W::WIDE).map(|sloc| escape(&sloc)).unwrap_or_else(|_| "null".to_owned());
+		let source = location.map(|location| escape(&format!("{}:{}", location.file, location.line))).unwrap_or_else(|| "null".to_owned());
+		let syscall = syscall.map(|number| number.to_string()).unwrap_or_else(|| "null".to_owned());
+		let error = error.map(|error| escape(&format!("{:?}", error))).unwrap_or_else(|| "null".to_owned());
+
+		writeln!(self.writer, "{{\"step\":{},\"pc\":{},\"next_pc\":{},\"word\":{},\"disasm\":{},\"source\":{},\"registers\":[{}],\"stack\":[{}],\"syscall\":{},\"error\":{}}}",
+			self.steps, pc, next, word, disassembly, source, registers.join(","), stack.join(","), syscall, error)
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+impl fmt::Debug for Tracer {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Tracer").field("steps", &self.steps).finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::parser::assemble_str;
+	use super::super::stream::{Buffer, Io};
+	use super::super::vm::{run_context, Context};
+
+	#[test]
+	fn every_step_is_one_json_line() {
+		let buffer = Buffer::default();
+		let (io, _) = Io::memory(b"");
+		let program = assemble_str("\tset r0 72\n\tpush r0\n\tpop r1\n\thlt\n", "test.rvm").unwrap().program();
+		let context: Context = Context { io, tracer: Some(Tracer::new(buffer.clone())), ..Context::from(program) };
+		assert!(run_context(context).is_ok());
+		let trace = String::from_utf8(buffer.contents()).unwrap();
+		let lines: Vec<&str> = trace.lines().collect();
+		assert_eq!(lines, vec![
+			r#"{"step":1,"pc":0,"next_pc":1,"word":"0x1048","disasm":"set r0 72","source":"test.rvm:1","registers":[{"register":"r0","old":0,"new":72}],"stack":[],"syscall":null,"error":null}"#,
+			r#"{"step":2,"pc":1,"next_pc":2,"word":"0x2000","disasm":"psh r0 r0","source":"test.rvm:2","registers":[{"register":"rd","old":0,"new":1}],"stack":[{"op":"push","index":0,"value":72}],"syscall":null,"error":null}"#,
+			r#"{"step":3,"pc":2,"next_pc":3,"word":"0x3101","disasm":"pop r1 r1","source":"test.rvm:3","registers":[{"register":"r1","old":0,"new":72},{"register":"rd","old":1,"new":0}],"stack":[{"op":"pop","index":0,"value":72}],"syscall":null,"error":null}"#,
+			r#"{"step":4,"pc":3,"next_pc":4,"word":"0xfc0c","disasm":"xor rs rs","source":"test.rvm:4","registers":[],"stack":[],"syscall":null,"error":null}"#,
+			r#"{"step":5,"pc":4,"next_pc":5,"word":"0x0000","disasm":"int","source":"test.rvm:4","registers":[],"stack":[],"syscall":0,"error":"VMHaltError"}"#
+		]);
+	}
+
+	#[test]
+	fn strings_are_escaped() {
+		assert_eq!(escape("a\"b\\c\n\t\u{1}"), r#""a\"b\\c\n\t\u0001""#);
+	}
+}
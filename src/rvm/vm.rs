@@ -1,94 +1,270 @@
 use std::cmp::Ordering;
-use std::char;
-use std::io;
+use std::error;
+use std::fmt;
+use std::io::{BufRead, Cursor, Read};
+use std::mem;
+use std::time::{Duration, Instant};
 use super::*;
+use super::syscall::Syscalls;
+use super::stream::Io;
+use super::trace::Tracer;
+use super::debuginfo::{DebugInfo, SourceLine};
+use super::word::Word;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect<W: Word = Rsize> {
+	Register { register: Rsize, old: W, new: W },
+	Push { index: usize, value: W },
+	Pop { index: usize, value: W },
+	Load { index: usize, value: W },
+	Store { index: usize, old: W, new: W },
+	Syscall(W)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Budget {
+	pub max_steps: Option<usize>,
+	pub deadline: Option<Instant>
+}
+
+impl Budget {
+	pub fn steps(max_steps: usize) -> Budget {
+		Budget { max_steps: Some(max_steps), deadline: None }
+	}
+
+	pub fn timeout(timeout: Duration) -> Budget {
+		Budget { max_steps: None, deadline: Some(Instant::now() + timeout) }
+	}
+
+	fn exhausted(&self, steps: usize) -> bool {
+		self.max_steps.is_some_and(|max_steps| steps >= max_steps) || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+}
 
 #[derive(Default, Debug)]
-pub struct Context {
-        pub registers: [Rsize; 13],
-        pub stack: Stack,
-	pub bytecode: Bytecode
+pub struct Context<W: Word = Rsize> {
+        pub registers: Registers<W>,
+        pub stack: Stack<W>,
+	pub bytecode: Bytecode,
+	pub syscalls: Syscalls<W>,
+	pub io: Io,
+	pub effects: Vec<Effect<W>>,
+	pub tracer: Option<Tracer>,
+	pub budget: Budget,
+	pub steps: usize,
+	pub debug_info: DebugInfo,
+	pub bank: W
 }
 
-impl Context {
+impl<W: Word> Context<W> {
+	pub fn pc(&self) -> usize {
+		self.bank.into() * (W::MAX.into() + 1) + self.registers[RN as usize].into()
+	}
+
+	fn write_register(&mut self, register: Rsize, value: W) {
+		let old = self.registers[register as usize];
+		self.registers[register as usize] = value;
+		self.effects.push(Effect::Register { register, old, new: value });
+	}
+
+	fn record_changes(&mut self, registers: &Registers<W>, stack: &Stack<W>) {
+		for (register, (old, new)) in registers.iter().zip(self.registers.iter()).enumerate() {
+			if old != new {
+				self.effects.push(Effect::Register { register: register as Rsize, old: *old, new: *new });
+			}
+		}
+		for (index, (old, new)) in stack.iter().zip(self.stack.iter()).enumerate() {
+			if old != new {
+				self.effects.push(Effect::Store { index, old: *old, new: *new });
+			}
+		}
+		for (index, value) in self.stack.iter().enumerate().skip(stack.len()) {
+			self.effects.push(Effect::Push { index, value: *value });
+		}
+		for (index, value) in stack.iter().enumerate().skip(self.stack.len()).rev() {
+			self.effects.push(Effect::Pop { index, value: *value });
+		}
+	}
+
 	fn fetch(&mut self) -> Result<Instruction, VMError> {
 		let pointer_next = self.registers[RN as usize];
-		if let Some(instruction) = self.bytecode.get(pointer_next as usize) {
-			if let Some(next) = pointer_next.checked_add(1) {
+		if let Some(instruction) = self.bytecode.get(self.pc()) {
+			if let Some(next) = pointer_next.checked_add(W::from(1)) {
 				self.registers[RN as usize] = next;
+			} else if self.bank < W::MAX {
+				self.registers[RN as usize] = W::default();
 			} else {
 				if let Ok(register) = decode_target(instruction) {
 					if register != RN {
-						return Err(VMError::VMContextFetchNextError)
+						return Err(VMError::VMContextFetchNextError { bank: self.bank.into(), offset: pointer_next.into() })
 					};
 				}
 			}
 			Ok(instruction.to_owned())
 		} else {
-			return Err(VMError::VMContextFetchInvalidError)
+			Err(VMError::VMContextFetchInvalidError { bank: self.bank.into(), offset: pointer_next.into() })
+		}
+	}
+
+	fn fetch_word(&mut self) -> Result<Instruction, VMError> {
+		let pointer_next = self.registers[RN as usize];
+		let bank = if pointer_next != W::default() {
+			self.bank
+		} else if let Some(bank) = self.bank.checked_add(W::from(1)) {
+			bank
+		} else {
+			return Err(VMError::VMContextFetchNextError { bank: self.bank.into(), offset: W::MAX.into() })
+		};
+		if let Some(&word) = self.bytecode.get(bank.into() * (W::MAX.into() + 1) + pointer_next.into()) {
+			if let Some(next) = pointer_next.checked_add(W::from(1)) {
+				self.registers[RN as usize] = next;
+			} else if bank < W::MAX {
+				self.registers[RN as usize] = W::default();
+			} else {
+				return Err(VMError::VMContextFetchNextError { bank: bank.into(), offset: pointer_next.into() })
+			}
+			Ok(word)
+		} else {
+			Err(VMError::VMContextFetchInvalidError { bank: bank.into(), offset: pointer_next.into() })
+		}
+	}
+
+	fn fetch_immediate(&mut self) -> Result<W, VMError> {
+		let immediate = self.fetch_word()?;
+		W::from_usize(immediate as usize).ok_or(VMError::VMInvalidValueError)
+	}
+
+	fn push(&mut self, value: W) -> Result<(), VMError> {
+		self.stack.push(value);
+		self.effects.push(Effect::Push { index: self.stack.len() - 1, value });
+		if let Some(new_rd) = self.registers[RD as usize].checked_add(W::from(1)) {
+			self.write_register(RD, new_rd);
+			Ok(())
+		} else {
+			Err(VMError::VMStackOverflowError)
+		}
+	}
+
+	fn pop(&mut self) -> Result<W, VMError> {
+		if let Some(value) = self.stack.pop() {
+			self.effects.push(Effect::Pop { index: self.stack.len(), value });
+			if let Some(new_rd) = self.registers[RD as usize].checked_sub(W::from(1)) {
+				self.write_register(RD, new_rd);
+				return Ok(value)
+			}
+		}
+		Err(VMError::VMStackOverflowError)
+	}
+
+	fn execute_secondary(&mut self, instruction: Instruction) -> Result<(), VMError> {
+		let secondary = decode_value(&instruction);
+		let (target, value) = decode_operands(secondary, self.fetch_word()?)?;
+		let current = self.registers[target as usize];
+		match secondary {
+			MOD | ADI | SBI | MLI | DVI => {
+				let operand = if secondary == MOD { self.registers[value as usize] } else { W::from(value) };
+				let result = match secondary {
+					MOD => current.checked_rem(operand),
+					ADI => current.checked_add(operand),
+					SBI => current.checked_sub(operand),
+					MLI => current.checked_mul(operand),
+					_ => current.checked_div(operand)
+				};
+				if let Some(result) = result {
+					self.write_register(target, result);
+				} else {
+					return Err(VMError::VMRegisterOverflowError)
+				}
+			},
+			NOT => self.write_register(target, !current),
+			SPT => {
+				let index = self.registers[value as usize].into();
+				if let Some(slot) = self.stack.get_mut(index) {
+					let old = mem::replace(slot, current);
+					self.effects.push(Effect::Store { index, old, new: current });
+				} else {
+					return Err(VMError::VMStackInvalidAccessError)
+				}
+			},
+			CAL => {
+				let next = self.registers[RN as usize];
+				// a call in the last two cells of a bank returns to the start of the next one
+				let bank = if next < W::from(2) { self.bank.checked_add(W::from(1)).unwrap_or(self.bank) } else { self.bank };
+				self.push(bank)?;
+				self.push(next)?;
+				self.write_register(RN, current);
+			},
+			RET => {
+				let next = self.pop()?;
+				let bank = self.pop()?;
+				self.write_register(RB, bank);
+				self.write_register(RN, next);
+			},
+			_ => return Err(VMError::VMInvalidOpcodeError)
+		}
+		Ok(())
+	}
+
+	fn switch_bank(&mut self, offset: W) {
+		if self.effects.iter().any(|effect| matches!(*effect, Effect::Register { register: RN, .. })) {
+			self.bank = self.registers[RB as usize];
+		} else if self.registers[RN as usize] <= offset && self.bank < W::MAX {
+			self.bank = self.bank.checked_add(W::from(1)).unwrap_or(W::MAX);
+			if !self.effects.iter().any(|effect| matches!(*effect, Effect::Register { register: RB, .. })) {
+				self.write_register(RB, self.bank);
+			}
 		}
 	}
 
 	fn step(&mut self) -> Result<Instruction, VMError> {
+		let pc = self.pc();
+		if self.budget.exhausted(self.steps) {
+			return Err(VMError::VMBudgetExhausted { steps: self.steps, pc })
+		}
+		self.steps += 1;
+		let offset = self.registers[RN as usize];
+		let result = self.execute();
+		if result.is_ok() {
+			self.switch_bank(offset);
+		}
+		let next = self.pc();
+		if let Some(tracer) = self.tracer.as_mut() {
+			if let Err(error) = tracer.record(pc, next, &self.bytecode, self.debug_info.get(pc), &self.effects, result.as_ref().err()) {
+				warn!("Writing trace failed, tracing disabled: {}", error);
+				self.tracer = None;
+			}
+		}
+		result
+	}
+
+	fn execute(&mut self) -> Result<Instruction, VMError> {
+		self.effects.clear();
 		match self.fetch() {
 			Ok(instruction) => {
 				match decode_opcode(&instruction) {
+					Ok(INT) if decode_interrupt(&instruction)?.is_some() => self.execute_secondary(instruction)?,
 					Ok(INT) => {
-						match self.registers[RS as usize] {
-							HALT => return Err(VMError::VMHaltError),
-							PRINTLINE => {
-								let mut index = self.registers[R0 as usize] as usize;
-								let mut string = String::new();
-								loop {
-									if let Some(raw) = self.stack.get(index as usize) {
-										if let Some(chr) = char::from_u32(raw.to_owned() as u32) {
-											string.push(chr);
-										} else {
-											break
-										}
-										index += 1;
-									} else {
-										break
-										//return Err(VMError::VMStackInvalidAccessError)
-									}
-								}
-								println!("{}", string);
-							},
-							READLINE => { 
-								let mut input = String::new();
-								let mut length = self.registers[R0 as usize] as usize;
-								let mut count = 0;
-								if let Ok(line) = io::stdin().read_line(&mut input) {
-									input.pop();
-									let mut chars = input.chars();
-									while let Some(chr) = chars.next() {
-										if count >= length {
-											break
-										}
-										self.stack.push(chr as u8);
-										if let Some(new_rd) = self.registers[RD as usize].checked_add(1) {
-											self.registers[RD as usize] = new_rd;
-										} else {
-											return Err(VMError::VMStackOverflowError)
-										}
-										count += 1;
-									};
-									self.registers[R0 as usize] = count as Rsize;
-									println!("R0 = {}",self.registers[R0 as usize]);
-								} else {
-									return Err(VMError::VMInterruptError)
-								}
-							},
-							_ => return Err(VMError::VMUnimplementedError)
-						}
+						let number = self.registers[RS as usize];
+						let registers = self.registers;
+						let stack = self.stack.clone();
+						self.effects.push(Effect::Syscall(number));
+						let result = self.syscalls.call(number, &mut self.registers, &mut self.stack, &mut self.io);
+						self.record_changes(&registers, &stack);
+						result?;
 					},
 					Ok(SET) => {
+						if W::WIDE && (instruction & 0x0F00) >> 8 == EXTENDED as Instruction {
+							let target = decode_value_as_register(&instruction)?;
+							let value = self.fetch_immediate()?;
+							self.write_register(target, value);
+							return Ok(instruction)
+						}
 						match decode_target(&instruction) {
 							Ok(target) => {
-								self.registers[target as usize] = decode_value(&instruction);
+								self.write_register(target, W::from(decode_value(&instruction)));
 								return Ok(instruction)
 							},
-							_ => return Err(VMError::VMUnimplementedError)
+							_ => return Err(VMError::VMInvalidTargetError)
 						}
 					},
 					Ok(PSH) => {
@@ -96,8 +272,9 @@ impl Context {
 							if let Ok(value) = decode_value_as_register(&instruction) {
 								for register in target.min(value)..target.max(value)+1 {
 									self.stack.push(self.registers[register as usize]);
-									if let Some(new_rd) = self.registers[RD as usize].checked_add(1) {
-										self.registers[RD as usize] = new_rd;
+									self.effects.push(Effect::Push { index: self.stack.len() - 1, value: self.registers[register as usize] });
+									if let Some(new_rd) = self.registers[RD as usize].checked_add(W::from(1)) {
+										self.write_register(RD, new_rd);
 									} else {
 										return Err(VMError::VMStackOverflowError)
 									}
@@ -115,12 +292,13 @@ impl Context {
 							if let Ok(value) = decode_value_as_register(&instruction) {
 								for register in (target.min(value)..target.max(value)+1).rev() {
 									if let Some(value) = self.stack.pop() {
-										self.registers[register as usize] = value;
+										self.effects.push(Effect::Pop { index: self.stack.len(), value });
+										self.write_register(register, value);
 									} else {
 										return Err(VMError::VMStackOverflowError)
 									};
-									if let Some(new_rd) = self.registers[RD as usize].checked_sub(1) {
-										self.registers[RD as usize] = new_rd;
+									if let Some(new_rd) = self.registers[RD as usize].checked_sub(W::from(1)) {
+										self.write_register(RD, new_rd);
 									} else {
 										return Err(VMError::VMStackOverflowError)
 									}
@@ -138,7 +316,7 @@ impl Context {
 							Ok(target) => {
 								if let Ok(value) = decode_value_as_register(&instruction) {
 									if let Some(new_value) = self.registers[target as usize].checked_add(self.registers[value as usize]) {
-										self.registers[target as usize] = new_value;
+										self.write_register(target, new_value);
 										return Ok(instruction)
 									} else {
 										return Err(VMError::VMRegisterOverflowError)
@@ -155,7 +333,7 @@ impl Context {
 							Ok(target) => {
 								if let Ok(value) = decode_value_as_register(&instruction) {
 									if let Some(new_value) = self.registers[target as usize].checked_sub(self.registers[value as usize]) {
-										self.registers[target as usize] = new_value;
+										self.write_register(target, new_value);
 										return Ok(instruction)
 									} else {
 										return Err(VMError::VMRegisterOverflowError)
@@ -172,7 +350,7 @@ impl Context {
 							Ok(target) => {
 								if let Ok(value) = decode_value_as_register(&instruction) {
 									if let Some(new_value) = self.registers[target as usize].checked_mul(self.registers[value as usize]) {
-										self.registers[target as usize] = new_value;
+										self.write_register(target, new_value);
 										return Ok(instruction)
 									} else {
 										return Err(VMError::VMRegisterOverflowError)
@@ -189,7 +367,7 @@ impl Context {
 							Ok(target) => {
 								if let Ok(value) = decode_value_as_register(&instruction) {
 									if let Some(new_value) = self.registers[target as usize].checked_div(self.registers[value as usize]) {
-										self.registers[target as usize] = new_value;
+										self.write_register(target, new_value);
 										return Ok(instruction)
 									} else {
 										return Err(VMError::VMRegisterOverflowError)
@@ -206,13 +384,13 @@ impl Context {
 							if let Ok(value) = decode_value_as_register(&instruction) {
 								match self.registers[target as usize].cmp(&self.registers[value as usize]) {
 									Ordering::Equal => {
-										self.registers[RF as usize] = EQ;
+										self.write_register(RF, W::from(EQ));
 									},
 									Ordering::Less => {
-										self.registers[RF as usize] = LE;
+										self.write_register(RF, W::from(LE));
 									},
 									Ordering::Greater => {
-										self.registers[RF as usize] = GR;
+										self.write_register(RF, W::from(GR));
 									}
 								}
 							} else {
@@ -226,7 +404,7 @@ impl Context {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
 								if self.registers[RF as usize] == self.registers[RC as usize] {
-									self.registers[target as usize] = self.registers[value as usize];
+									self.write_register(target, self.registers[value as usize]);
 								}
 							} else {
 								return Err(VMError::VMInvalidValueError)
@@ -238,8 +416,10 @@ impl Context {
 					Ok(LPT) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								if let Some(resolved) = self.stack.get(self.registers[value as usize] as usize) {
-									self.registers[target as usize] = resolved.to_owned();
+								let index = self.registers[value as usize].into();
+								if let Some(resolved) = self.stack.get(index).cloned() {
+									self.effects.push(Effect::Load { index, value: resolved });
+									self.write_register(target, resolved);
 								} else {
 									return Err(VMError::VMStackInvalidAccessError)
 								}
@@ -253,7 +433,7 @@ impl Context {
 					Ok(LSH) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								self.registers[target as usize] <<= self.registers[value as usize];
+								self.write_register(target, self.registers[target as usize] << self.registers[value as usize]);
 							} else {
 								return Err(VMError::VMInvalidValueError)
 							}
@@ -264,7 +444,7 @@ impl Context {
 					Ok(RSH) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								self.registers[target as usize] >>= self.registers[value as usize];
+								self.write_register(target, self.registers[target as usize] >> self.registers[value as usize]);
 							} else {
 								return Err(VMError::VMInvalidValueError)
 							}
@@ -275,7 +455,7 @@ impl Context {
 					Ok(AND) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								self.registers[target as usize] &= self.registers[value as usize];
+								self.write_register(target, self.registers[target as usize] & self.registers[value as usize]);
 							} else {
 								return Err(VMError::VMInvalidValueError)
 							}
@@ -286,7 +466,7 @@ impl Context {
 					Ok(BOR) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								self.registers[target as usize] |= self.registers[value as usize];
+								self.write_register(target, self.registers[target as usize] | self.registers[value as usize]);
 							} else {
 								return Err(VMError::VMInvalidValueError)
 							}
@@ -297,7 +477,7 @@ impl Context {
 					Ok(XOR) => {
 						if let Ok(target) = decode_target(&instruction) {
 							if let Ok(value) = decode_value_as_register(&instruction) {
-								self.registers[target as usize] ^= self.registers[value as usize];
+								self.write_register(target, self.registers[target as usize] ^ self.registers[value as usize]);
 							} else {
 								return Err(VMError::VMInvalidValueError)
 							}
@@ -307,70 +487,258 @@ impl Context {
 					},
 					_ => return Err(VMError::VMInvalidOpcodeError)
 				}
-				return Ok(instruction)
+				Ok(instruction)
 			},
-			Err(error) => return Err(error)
+			Err(error) => Err(error)
 		}
 	}
 }
 
-pub fn run(bytecode: Bytecode) -> Result<Context, VMError> {
-	let mut context = Context::default();	
-	context.bytecode = bytecode;
+impl<W: Word> From<rvb::Program> for Context<W> {
+	fn from(program: rvb::Program) -> Context<W> {
+		if program.version != W::VERSION {
+			warn!("Loading an ISA version {} program into a version {} machine", program.version, W::VERSION);
+		}
+		let stack = W::decode_stack(&program.stack);
+		let bank_size = W::MAX.into() + 1;
+		let bank = W::from_usize(program.entry as usize / bank_size).unwrap_or_default();
+		let mut registers = Registers::<W>::default();
+		registers[RN as usize] = W::from_usize(program.entry as usize % bank_size).unwrap_or_default();
+		registers[RB as usize] = bank;
+		registers[RD as usize] = W::from_usize(stack.len()).unwrap_or(W::MAX);
+		let debug_info = DebugInfo::decode(&program.debug).unwrap_or_else(|error| {
+			warn!("Ignoring invalid debug info: {}", error);
+			DebugInfo::default()
+		});
+		Context { registers, stack, bytecode: program.bytecode, debug_info, bank, ..Default::default() }
+	}
+}
 
-	loop {
-		match context.step()	{
-			Ok(instruction) => debug!("Step {:x} ok, trace registers: {:?}", instruction, context.registers),
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watchpoint<W: Word = Rsize> {
+	Pc(usize),
+	RegisterWrite(Rsize),
+	RegisterValue(Rsize, W),
+	StackRead(usize),
+	StackWrite(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stop<W: Word = Rsize> {
+	pub watchpoint: Watchpoint<W>,
+	pub pc: usize,
+	pub instruction: Instruction,
+	pub before: Option<W>,
+	pub after: Option<W>
+}
+
+#[derive(Debug)]
+pub enum StepOutcome<W: Word = Rsize> {
+	Continued,
+	Stopped(Vec<Stop<W>>),
+	Halted,
+	Faulted(VMError),
+	WaitingForInput
+}
+
+#[derive(Debug)]
+pub struct Vm<W: Word = Rsize> {
+	pub context: Context<W>,
+	pub watchpoints: Vec<Watchpoint<W>>,
+	halted: bool,
+	fault: Option<VMError>
+}
+
+impl<W: Word> Vm<W> {
+	pub fn new(context: Context<W>) -> Vm<W> {
+		Vm { context, watchpoints: Vec::new(), halted: false, fault: None }
+	}
+
+	pub fn steps(&self) -> usize {
+		self.context.steps
+	}
+
+	pub fn is_halted(&self) -> bool {
+		self.halted
+	}
+
+	pub fn fault(&self) -> Option<&VMError> {
+		self.fault.as_ref()
+	}
+
+	pub fn pc(&self) -> usize {
+		self.context.pc()
+	}
+
+	pub fn feed_input(&mut self, input: &[u8]) {
+		let previous = mem::replace(&mut self.context.io.input, Box::new(Cursor::new(Vec::new())));
+		self.context.io.input = Box::new(previous.chain(Cursor::new(input.to_vec())));
+	}
+
+	fn waiting_for_input(&mut self) -> bool {
+		if self.context.registers[RS as usize] != W::from(READLINE) {
+			return false
+		}
+		match self.context.bytecode.get(self.pc()).filter(|&instruction| instruction & 0x0F00 == 0).map(decode_opcode) {
+			Some(Ok(INT)) => self.context.io.input.fill_buf().map(|buffer| buffer.is_empty()).unwrap_or(false),
+			_ => false
+		}
+	}
+
+	fn check_watchpoints(&self, pc: usize, offset: W, instruction: Instruction) -> Vec<Stop<W>> {
+		let mut stops = Vec::new();
+		for watchpoint in &self.watchpoints {
+			let stop = |before, after| Stop { watchpoint: *watchpoint, pc, instruction, before, after };
+			if let Watchpoint::Pc(index) = *watchpoint {
+				if self.pc() == index {
+					stops.push(stop(Some(offset), Some(self.context.registers[RN as usize])));
+				}
+				continue
+			}
+			for effect in &self.context.effects {
+				match (*watchpoint, *effect) {
+					(Watchpoint::RegisterWrite(watched), Effect::Register { register, old, new }) if watched == register => stops.push(stop(Some(old), Some(new))),
+					(Watchpoint::RegisterValue(watched, value), Effect::Register { register, old, new }) if watched == register && old != value && new == value => stops.push(stop(Some(old), Some(new))),
+					(Watchpoint::StackRead(slot), Effect::Pop { index, value }) if slot == index => stops.push(stop(Some(value), None)),
+					(Watchpoint::StackRead(slot), Effect::Load { index, value }) if slot == index => stops.push(stop(Some(value), Some(value))),
+					(Watchpoint::StackWrite(slot), Effect::Push { index, value }) if slot == index => stops.push(stop(None, Some(value))),
+					(Watchpoint::StackWrite(slot), Effect::Store { index, old, new }) if slot == index => stops.push(stop(Some(old), Some(new))),
+					_ => {}
+				}
+			}
+		}
+		stops
+	}
+
+	pub fn step(&mut self) -> StepOutcome<W> {
+		if self.halted {
+			return StepOutcome::Halted
+		}
+		if let Some(ref error) = self.fault {
+			return StepOutcome::Faulted(error.repeat())
+		}
+		if self.waiting_for_input() {
+			return StepOutcome::WaitingForInput
+		}
+		let pc = self.pc();
+		let offset = self.context.registers[RN as usize];
+		match self.context.step() {
+			Ok(instruction) => {
+				debug!("Step {:x} ok, trace registers: {:?}", instruction, self.context.registers);
+				let stops = self.check_watchpoints(pc, offset, instruction);
+				if stops.is_empty() { StepOutcome::Continued } else { StepOutcome::Stopped(stops) }
+			},
+			Err(VMError::VMHaltError) => { self.halted = true; StepOutcome::Halted },
+			Err(VMError::VMBudgetExhausted { steps, pc }) => StepOutcome::Faulted(VMError::VMBudgetExhausted { steps, pc }),
 			Err(error) => {
-				let faulty_index = context.registers[RN as usize];
-				match error {
-					VMError::VMContextFetchNextError => {
-						println!("Error while fetching instruction at index 0x{:x}\n\t-> Hint: Programm too large?", faulty_index);
-						break
-					}
-					VMError::VMContextFetchInvalidError => {
-						println!("Error while fetching instruction at index 0x{:x}\n\t-> Hint: No instruction found at this index", faulty_index);
-						break
-					}
-					VMError::VMUnimplementedError => {
-						println!("Error while decoding instruction\n\t-> Hint: Instruction not implemented (yet)");
-						break
-					}
-					VMError::VMInvalidOpcodeError => {
-						println!("Error while decoding instruction\n\t-> Hint: Invalid Opcode)");
-						break
-					}
-					VMError::VMInvalidTargetError => {
-						println!("Error while decoding instruction\n\t-> Hint: Invalid Target");
-						break
-					}
-					VMError::VMInvalidValueError => {
-						println!("Error while decoding instruction\n\t-> Hint: Invalid Value");
-						break
-					}
-					VMError::VMRegisterOverflowError => {
-						println!("Error while decoding instruction\n\t-> Hint: Register overflow / underflow");
-					}
-					VMError::VMStackOverflowError => {
-						println!("Error while decoding instruction\n\t-> Hint: Stack overflow");
-					}
-					VMError::VMStackInvalidAccessError => {
-						println!("Error while decoding instruction\n\t-> Hint: Invalid Stack access");
-					}
-					VMError::VMInterruptError => {
-						println!("Error while decoding instruction\n\t-> Hint: Interrupt Error");
-					}
-					VMError::VMHaltError => break,
-				};
-				break
+				self.fault = Some(error.repeat());
+				StepOutcome::Faulted(error)
 			}
-		}; 
+		}
+	}
+
+	pub fn run_for(&mut self, steps: usize) -> StepOutcome<W> {
+		for _ in 0..steps {
+			match self.step() {
+				StepOutcome::Continued => {},
+				outcome => return outcome
+			}
+		}
+		StepOutcome::Continued
+	}
+
+	pub fn run_until<P: FnMut(&Context<W>) -> bool>(&mut self, mut predicate: P) -> StepOutcome<W> {
+		loop {
+			match self.step() {
+				StepOutcome::Continued => if predicate(&self.context) { return StepOutcome::Continued },
+				outcome => return outcome
+			}
+		}
 	}
+}
+
+impl<W: Word> From<Context<W>> for Vm<W> {
+	fn from(context: Context<W>) -> Vm<W> {
+		Vm::new(context)
+	}
+}
+
+pub fn run(bytecode: Bytecode) -> Result<Context, Fault> {
+	run_program(rvb::Program::new(bytecode))
+}
+
+pub fn run_with_io(bytecode: Bytecode, io: Io) -> Result<Context, Fault> {
+	run_context(Context { io, ..Context::from(rvb::Program::new(bytecode)) })
+}
+
+pub fn run_program(program: rvb::Program) -> Result<Context, Fault> {
+	run_context(Context::from(program))
+}
 
-	Ok(context)
+#[derive(Debug)]
+pub struct Fault<W: Word = Rsize> {
+	pub error: VMError,
+	pub pc: usize,
+	pub instruction: Option<Instruction>,
+	pub disassembly: Option<Sloc>,
+	pub location: Option<Box<SourceLine>>,
+	pub context: Box<Context<W>>
 }
 
-fn decode_opcode(instruction: &Instruction) -> Result<Rsize, VMError> {
+impl<W: Word> Fault<W> {
+	fn new(error: VMError, pc: usize, context: Context<W>) -> Fault<W> {
+		let instruction = context.bytecode.get(pc).cloned();
+		let disassembly = disasm::disassemble_at(&context.bytecode, pc, W::WIDE).ok();
+		let location = context.debug_info.get(pc).cloned().map(Box::new);
+		Fault { error, pc, instruction, disassembly, location, context: Box::new(context) }
+	}
+}
+
+impl<W: Word> fmt::Display for Fault<W> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match (self.instruction, &self.disassembly) {
+			(Some(instruction), Some(sloc)) => writeln!(f, "Error at index 0x{:x}: 0x{:04x}\t{}", self.pc, instruction, sloc)?,
+			(Some(instruction), None) => writeln!(f, "Error at index 0x{:x}: 0x{:04x}", self.pc, instruction)?,
+			_ => writeln!(f, "Error at index 0x{:x}", self.pc)?
+		}
+		if let Some(ref location) = self.location {
+			writeln!(f, "\t-> At {}", location)?;
+		}
+		match self.error {
+			VMError::VMSyscallError(ref error) => write!(f, "\t-> Hint: Syscall 0x{:x} failed: {}", self.context.registers[RS as usize], error),
+			ref error => write!(f, "\t-> Hint: {}", error)
+		}
+	}
+}
+
+impl<W: Word> error::Error for Fault<W> {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		Some(&self.error)
+	}
+}
+
+pub fn run_context<W: Word>(mut context: Context<W>) -> Result<Context<W>, Fault<W>> {
+	let result = loop {
+		let pc = context.pc();
+		match context.step()	{
+			Ok(instruction) => debug!("Step {:x} ok, trace registers: {:?}", instruction, context.registers),
+			Err(VMError::VMHaltError) => break Ok(()),
+			Err(error) => break Err((error, pc))
+		}; 
+	};
+
+	if let Some(tracer) = context.tracer.as_mut() {
+		if let Err(error) = tracer.flush() {
+			warn!("Writing trace failed: {}", error);
+		}
+	}
+	match result {
+		Ok(()) => Ok(context),
+		Err((error, pc)) => Err(Fault::new(error, pc, context))
+	}
+}
+
+pub(crate) fn decode_opcode(instruction: &Instruction) -> Result<Rsize, VMError> {
 	let result = ((instruction & 0xF000) >> 12) as Rsize;
 	if result <= 0xf {
 		Ok(result)
@@ -379,24 +747,294 @@ fn decode_opcode(instruction: &Instruction) -> Result<Rsize, VMError> {
 	}
 }
 
-fn decode_target(instruction: &Instruction) -> Result<Rsize, VMError> {
+pub(crate) fn is_escape(instruction: Instruction) -> bool {
+	matches!(decode_opcode(&instruction), Ok(INT)) && (instruction & 0x0F00) >> 8 == EXTENDED as Instruction
+}
+
+pub(crate) fn decode_interrupt(instruction: &Instruction) -> Result<Option<Rsize>, VMError> {
+	if is_escape(*instruction) {
+		let secondary = decode_value(instruction);
+		if !SECONDARY.iter().any(|&(_, number, _)| number == secondary) {
+			return Err(VMError::VMInvalidOpcodeError)
+		}
+		return Ok(Some(secondary))
+	}
+	if instruction & 0x0F00 != 0 {
+		return Err(VMError::VMInvalidTargetError)
+	}
+	if instruction & 0x00FF != 0 {
+		return Err(VMError::VMInvalidValueError)
+	}
+	Ok(None)
+}
+
+pub(crate) fn decode_operands(secondary: Rsize, operands: Instruction) -> Result<(Rsize, Rsize), VMError> {
+	let arity = if let Some(&(_, _, arity)) = SECONDARY.iter().find(|&&(_, number, _)| number == secondary) { arity } else { return Err(VMError::VMInvalidOpcodeError) };
+	if operands & 0xF000 != 0 {
+		return Err(VMError::VMInvalidValueError)
+	}
+	let target = if arity >= 1 { decode_target(&operands)? } else if operands & 0x0F00 == 0 { R0 } else { return Err(VMError::VMInvalidTargetError) };
+	let value = if arity < 2 {
+		if operands & 0x00FF != 0 {
+			return Err(VMError::VMInvalidValueError)
+		}
+		R0
+	} else if secondary & 0xF0 == IMMEDIATE {
+		decode_value(&operands)
+	} else {
+		decode_value_as_register(&operands)?
+	};
+	Ok((target, value))
+}
+
+pub(crate) fn decode_target(instruction: &Instruction) -> Result<Rsize, VMError> {
 	let result = ((instruction & 0x0F00) >> 8) as Rsize;
-	if result <= RS {
+	if result <= RB {
 		Ok(result)
 	} else {
 		Err(VMError::VMInvalidTargetError)
 	}
 }
 
-fn decode_value_as_register(instruction: &Instruction) -> Result<Rsize, VMError> {
+pub(crate) fn decode_value_as_register(instruction: &Instruction) -> Result<Rsize, VMError> {
 	let result = (instruction & 0x00FF) as Rsize;
-	if result <= RS {
+	if result <= RB {
 		Ok(result)
 	} else {
 		Err(VMError::VMInvalidTargetError)
 	}
 }
 
-fn decode_value(instruction: &Instruction) -> Rsize {
-	((instruction & 0x00FF)) as Rsize
+pub(crate) fn decode_value(instruction: &Instruction) -> Rsize {
+	(instruction & 0x00FF) as Rsize
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::parser::assemble_str;
+	use super::super::disasm::disassemble_at;
+
+	fn machine(source: &str) -> Vm {
+		let (io, _) = Io::memory(b"");
+		Vm::new(Context { io, ..Context::from(assemble_str(source, "test.rvm").unwrap().program()) })
+	}
+
+	#[test]
+	fn step_executes_one_instruction() {
+		let mut vm = machine("\tset r0 7\n\tset r1 2\n\tadd r0 r1\n\thlt\n");
+		assert!(matches!(vm.step(), StepOutcome::Continued));
+		assert_eq!(vm.context.registers[R0 as usize], 7);
+		assert_eq!(vm.pc(), 1);
+		assert_eq!(vm.steps(), 1);
+		assert!(matches!(vm.run_for(2), StepOutcome::Continued));
+		assert_eq!(vm.context.registers[R0 as usize], 9);
+		assert!(matches!(vm.run_for(10), StepOutcome::Halted));
+		assert!(vm.is_halted());
+		assert!(matches!(vm.step(), StepOutcome::Halted));
+		assert_eq!(vm.steps(), 5);
+	}
+
+	#[test]
+	fn run_until_stops_when_the_predicate_holds() {
+		let mut vm = machine("\tset r1 1\nloop:\tadd r0 r1\n\tjmp loop\n");
+		assert!(matches!(vm.run_until(|context| context.registers[R0 as usize] == 5), StepOutcome::Continued));
+		assert_eq!(vm.context.registers[R0 as usize], 5);
+		assert_eq!(vm.pc(), 2);
+	}
+
+	#[test]
+	fn faults_are_sticky() {
+		let mut vm = machine("\tset r0 255\n\tset r1 1\n\tadd r0 r1\n\tset r2 9\n\thlt\n");
+		assert!(matches!(vm.run_for(10), StepOutcome::Faulted(VMError::VMRegisterOverflowError)));
+		let steps = vm.steps();
+		for _ in 0..3 {
+			assert!(matches!(vm.step(), StepOutcome::Faulted(VMError::VMRegisterOverflowError)));
+		}
+		assert!(matches!(vm.run_for(5), StepOutcome::Faulted(VMError::VMRegisterOverflowError)));
+		assert!(matches!(vm.fault(), Some(VMError::VMRegisterOverflowError)));
+		assert_eq!(vm.steps(), steps);
+		assert_eq!(vm.context.registers[R2 as usize], 0);
+	}
+
+	#[test]
+	fn syscall_faults_repeat_their_message() {
+		let mut vm = machine("\tset rs 9\n\tint\n");
+		vm.context.syscalls.register(9, |_: &mut Registers, _: &mut Stack, _: &mut Io| Err(VMError::VMSyscallError("no device".into())));
+		assert!(matches!(vm.run_for(5), StepOutcome::Faulted(VMError::VMSyscallError(_))));
+		match vm.step() {
+			StepOutcome::Faulted(error) => assert_eq!(error.to_string(), "Syscall failed: no device"),
+			outcome => panic!("unexpected {:?}", outcome)
+		}
+	}
+
+	#[test]
+	fn exhausted_budget_resumes() {
+		let mut vm = machine("\tset r0 1\n\tset r1 2\n\thlt\n");
+		vm.context.budget = Budget::steps(1);
+		assert!(matches!(vm.run_for(5), StepOutcome::Faulted(VMError::VMBudgetExhausted { steps: 1, pc: 1 })));
+		vm.context.budget = Budget::steps(10);
+		assert!(matches!(vm.run_for(5), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R1 as usize], 2);
+	}
+
+	#[test]
+	fn watchpoints_report_register_and_stack_effects() {
+		let mut vm = machine("\tset r0 3\n\tpsh r0 r0\n\tset r0 4\n\tset r1 0\n\tlpt r2 r1\n\thlt\n");
+		vm.watchpoints = vec![Watchpoint::StackWrite(0), Watchpoint::RegisterValue(R0, 4), Watchpoint::StackRead(0)];
+		match vm.run_for(10) {
+			StepOutcome::Stopped(stops) => {
+				assert_eq!(stops, vec![Stop { watchpoint: Watchpoint::StackWrite(0), pc: 1, instruction: 0x2000, before: None, after: Some(3) }]);
+			},
+			outcome => panic!("unexpected {:?}", outcome)
+		}
+		match vm.run_for(10) {
+			StepOutcome::Stopped(stops) => assert_eq!(stops[0].watchpoint, Watchpoint::RegisterValue(R0, 4)),
+			outcome => panic!("unexpected {:?}", outcome)
+		}
+		match vm.run_for(10) {
+			StepOutcome::Stopped(stops) => assert_eq!((stops[0].pc, stops[0].before, stops[0].after), (4, Some(3), Some(3))),
+			outcome => panic!("unexpected {:?}", outcome)
+		}
+		assert!(matches!(vm.run_for(10), StepOutcome::Halted));
+	}
+
+	#[test]
+	fn pc_watchpoint_stops_before_the_instruction() {
+		let mut vm = machine("\tset r0 1\ntarget:\tset r0 2\n\thlt\n");
+		vm.watchpoints.push(Watchpoint::Pc(1));
+		assert!(matches!(vm.run_for(10), StepOutcome::Stopped(_)));
+		assert_eq!(vm.pc(), 1);
+		assert_eq!(vm.context.registers[R0 as usize], 1);
+		assert!(matches!(vm.context.effects.as_slice(), [Effect::Register { register: R0, new: 1, .. }]));
+	}
+
+	#[test]
+	fn far_jump_straddling_a_bank_boundary_keeps_its_bank() {
+		let source = format!("{}\tljmp far\n\thlt\n.bank 2\nfar:\tset r0 7\n\thlt\n", "\tnop\n".repeat(255));
+		let mut vm = machine(&source);
+		assert!(matches!(vm.run_for(1000), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R0 as usize], 7);
+		assert_eq!(vm.context.registers[RB as usize], 2);
+	}
+
+	#[test]
+	fn falling_off_a_bank_advances_rb() {
+		let source = format!("{}\tset r0 1\n\tset r1 2\n\thlt\n", "\tnop\n".repeat(254));
+		let mut vm = machine(&source);
+		assert!(matches!(vm.run_for(1000), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R1 as usize], 2);
+		assert_eq!(vm.context.registers[RB as usize], 1);
+		assert_eq!(vm.pc(), 0x102);
+	}
+
+	#[test]
+	fn extended_instructions_straddle_a_bank_end() {
+		for padding in [254, 255] {
+			let source = format!("\tset r0 5\n{}\tadi r0 2\n\tset r1 1\n\thlt\n", "\tnop\n".repeat(padding - 1));
+			let mut vm = machine(&source);
+			assert!(matches!(vm.run_for(1000), StepOutcome::Halted), "adi at offset {:#x}", padding);
+			assert_eq!(vm.context.registers[R0 as usize], 7);
+			assert_eq!(vm.context.registers[R1 as usize], 1);
+			assert_eq!(vm.context.registers[RB as usize], 1);
+			assert_eq!(vm.pc(), padding + 5);
+		}
+	}
+
+	#[test]
+	fn int_words_are_checked_like_the_disassembler_does() {
+		for &(word, valid) in &[(0x0000, true), (0x00ff, false), (0x0100, false), (0x0f03, true), (0x0f05, false), (0x0f20, false)] {
+			let (io, _) = Io::memory(b"");
+			let mut vm: Vm = Vm::new(Context { io, ..Context::from(rvb::Program::new(vec![0x1c00, word, 0x0100])) });
+			vm.run_for(3);
+			let faulted = matches!(vm.fault(), Some(VMError::VMInvalidTargetError) | Some(VMError::VMInvalidValueError) | Some(VMError::VMInvalidOpcodeError));
+			assert_eq!(disassemble_at(&[word, 0x0100], 0, false).is_ok(), valid, "disassembling {:#06x}", word);
+			assert_eq!(!faulted, valid, "running {:#06x}", word);
+		}
+	}
+
+	#[test]
+	fn far_calls_return_to_the_bank_of_the_caller() {
+		let source = "\tset rb (far) >> 8\n\tset r3 (far) & 0xff\n\tcal r3\n\tset r1 2\n\thlt\n.bank 1\nfar:\tset r0 1\n\tret\n";
+		let mut vm = machine(source);
+		assert!(matches!(vm.run_for(1000), StepOutcome::Halted));
+		assert_eq!((vm.context.registers[R0 as usize], vm.context.registers[R1 as usize]), (1, 2));
+		assert_eq!(vm.context.registers[RB as usize], 0);
+		assert_eq!(vm.context.registers[RD as usize], 0);
+	}
+
+	#[test]
+	fn calls_at_a_bank_end_return_into_the_next_bank() {
+		for padding in [254, 255] {
+			let source = format!("{}\tcal r3\n\tset r1 2\n\thlt\nsub:\tset r0 1\n\tret\n", "\tnop\n".repeat(padding));
+			let mut vm = machine(&source);
+			let sub = padding + 5 - 0x100;
+			vm.context.registers[R3 as usize] = sub as u8;
+			vm.context.registers[RB as usize] = 1;
+			assert!(matches!(vm.run_for(1000), StepOutcome::Halted), "cal at offset {:#x}", padding);
+			assert_eq!((vm.context.registers[R0 as usize], vm.context.registers[R1 as usize]), (1, 2));
+			assert_eq!(vm.context.registers[RB as usize], 1);
+		}
+	}
+
+	#[test]
+	fn wide_machines_hold_16bit_registers_and_cells() {
+		let (io, _) = Io::memory(b"");
+		let program = assemble_str(".wide\n.byte 40000\n\tset r0 1000\n\tset r1 300\n\tadd r0 r1\n\tpush r0\n\tset r2 0\n\tlpt r3 r2\n\thlt\n", "test.rvm").unwrap().program();
+		assert!(program.is_wide());
+		let mut vm = Vm::new(Context::<u16> { io, ..Context::from(program) });
+		assert!(matches!(vm.run_for(100), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R0 as usize], 1300);
+		assert_eq!(vm.context.registers[R3 as usize], 40000);
+		assert_eq!(vm.context.stack, vec![40000, 1300]);
+	}
+
+	#[test]
+	fn secondary_instructions_execute() {
+		let mut vm = machine("\tset r0 17\n\tset r1 5\n\tmod r0 r1\n\tadi r0 40\n\tsbi r0 2\n\tmli r0 3\n\tdvi r0 4\n\tset r2 0\n\tnot r2\n\thlt\n");
+		assert!(matches!(vm.run_for(100), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R0 as usize], 30);
+		assert_eq!(vm.context.registers[R2 as usize], 0xff);
+		let mut vm = machine("\tset r0 250\n\tadi r0 10\n");
+		assert!(matches!(vm.run_for(100), StepOutcome::Faulted(VMError::VMRegisterOverflowError)));
+	}
+
+	#[test]
+	fn isaversion_reports_the_extension_level() {
+		let mut vm = machine("\tset rs ISAVERSION\n\tint\n\thlt\n");
+		assert!(matches!(vm.run_for(100), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R0 as usize], <u8 as Word>::VERSION);
+		assert_eq!(vm.context.registers[R1 as usize], EXTENSION_LEVEL);
+	}
+
+	#[test]
+	fn halting_returns_the_context() {
+		let (io, _) = Io::memory(b"");
+		let context: Context = Context { io, ..Context::from(assemble_str("\tset r0 3\n\thlt\n", "test.rvm").unwrap().program()) };
+		let context = run_context(context).unwrap();
+		assert_eq!(context.registers[R0 as usize], 3);
+	}
+
+	#[test]
+	fn faults_carry_the_failing_instruction() {
+		let (io, _) = Io::memory(b"");
+		let context: Context = Context { io, ..Context::from(assemble_str("\tset r0 1\nboom:\tdiv r0 r1\n", "test.rvm").unwrap().program()) };
+		let fault = run_context(context).unwrap_err();
+		assert!(matches!(fault.error, VMError::VMRegisterOverflowError));
+		assert_eq!(fault.pc, 1);
+		assert_eq!(fault.instruction, Some(0x7001));
+		assert_eq!(fault.disassembly.as_deref(), Some("div r0 r1"));
+		let location = fault.location.as_deref().unwrap();
+		assert_eq!((location.file.as_str(), location.line, location.label.as_deref()), ("test.rvm", 2, Some("boom")));
+		assert_eq!(fault.context.registers[R0 as usize], 1);
+		assert_eq!(fault.to_string(), "Error at index 0x1: 0x7001\tdiv r0 r1\n\t-> At test.rvm:2: boom:\tdiv r0 r1 (in boom)\n\t-> Hint: Register overflow / underflow");
+	}
+
+	#[test]
+	fn conditional_jump_ending_a_bank_stays_in_it() {
+		let source = format!("\tjmp go\ndone:\tset r2 9\n\thlt\ngo:\n{}\tjeq r0 r0 done\n\tset r2 1\n\thlt\n", "\tnop\n".repeat(248));
+		let mut vm = machine(&source);
+		assert!(matches!(vm.run_for(1000), StepOutcome::Halted));
+		assert_eq!(vm.context.registers[R2 as usize], 9);
+	}
 }
@@ -0,0 +1,58 @@
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{BitAnd, BitOr, BitXor, Not, Shl, Shr};
+
+pub trait Word: Copy + Default + Ord + Hash + fmt::Debug + fmt::Display + fmt::LowerHex + From<u8> + Into<usize> + 'static
+	+ Shl<Output = Self> + Shr<Output = Self> + BitAnd<Output = Self> + BitOr<Output = Self> + BitXor<Output = Self> + Not<Output = Self> {
+	const MAX: Self;
+	const VERSION: u8;
+	const WIDE: bool;
+
+	fn checked_add(self, other: Self) -> Option<Self>;
+	fn checked_sub(self, other: Self) -> Option<Self>;
+	fn checked_mul(self, other: Self) -> Option<Self>;
+	fn checked_div(self, other: Self) -> Option<Self>;
+	fn checked_rem(self, other: Self) -> Option<Self>;
+	fn from_usize(value: usize) -> Option<Self>;
+	fn decode_stack(bytes: &[u8]) -> Vec<Self>;
+}
+
+impl Word for u8 {
+	const MAX: u8 = u8::MAX;
+	const VERSION: u8 = 0x1;
+	const WIDE: bool = false;
+
+	fn checked_add(self, other: u8) -> Option<u8> { self.checked_add(other) }
+	fn checked_sub(self, other: u8) -> Option<u8> { self.checked_sub(other) }
+	fn checked_mul(self, other: u8) -> Option<u8> { self.checked_mul(other) }
+	fn checked_div(self, other: u8) -> Option<u8> { self.checked_div(other) }
+	fn checked_rem(self, other: u8) -> Option<u8> { self.checked_rem(other) }
+
+	fn from_usize(value: usize) -> Option<u8> {
+		if value <= u8::MAX as usize { Some(value as u8) } else { None }
+	}
+
+	fn decode_stack(bytes: &[u8]) -> Vec<u8> {
+		bytes.to_vec()
+	}
+}
+
+impl Word for u16 {
+	const MAX: u16 = u16::MAX;
+	const VERSION: u8 = 0x2;
+	const WIDE: bool = true;
+
+	fn checked_add(self, other: u16) -> Option<u16> { self.checked_add(other) }
+	fn checked_sub(self, other: u16) -> Option<u16> { self.checked_sub(other) }
+	fn checked_mul(self, other: u16) -> Option<u16> { self.checked_mul(other) }
+	fn checked_div(self, other: u16) -> Option<u16> { self.checked_div(other) }
+	fn checked_rem(self, other: u16) -> Option<u16> { self.checked_rem(other) }
+
+	fn from_usize(value: usize) -> Option<u16> {
+		if value <= u16::MAX as usize { Some(value as u16) } else { None }
+	}
+
+	fn decode_stack(bytes: &[u8]) -> Vec<u16> {
+		bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect()
+	}
+}